@@ -1,7 +1,9 @@
 use crate::{
     raytracer, 
+    camera::Camera,
+    csg::Csg,
     object::{Sphere, Floor, Hittable}, 
-    math::Vec3, 
+    math::{Float, Vec3}, 
     reflection::{Diffuse, Mirror, Glass, DiffusedLightSource},
 };
 use eframe::egui;
@@ -22,6 +24,12 @@ impl MyApp {
     }
 }
 
+impl Default for MyApp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -58,15 +66,18 @@ fn load_image_raytracer() -> egui::ColorImage {
             0.3,
             Glass::new(Vec3::new(0.9, 0.9, 0.9), 2.0),
         )),
-        Box::new(Sphere::new(
-            Vec3::new(-0.2, 0.5, -0.3),
-            0.2,
-            Glass::new(Vec3::new(0.9, 0.9, 0.7), 1.3),
-        )),
-        Box::new(Sphere::new(
-            Vec3::new(-0.2, 0.5, -0.3),
-            0.17,
-            Glass::new(Vec3::new(1.0, 1.0, 1.0), 1.0 / 1.3),
+        // Hollow glass shell.
+        Box::new(Csg::difference(
+            Sphere::new(
+                Vec3::new(-0.2, 0.5, -0.3),
+                0.2,
+                Glass::new(Vec3::new(0.9, 0.9, 0.7), 1.3),
+            ),
+            Sphere::new(
+                Vec3::new(-0.2, 0.5, -0.3),
+                0.17,
+                Glass::new(Vec3::new(0.9, 0.9, 0.7), 1.3),
+            ),
         )),
         Box::new(Sphere::new(
             Vec3::new(-1.0, 5.0, 0.1),
@@ -84,13 +95,20 @@ fn load_image_raytracer() -> egui::ColorImage {
             Diffuse::new(Vec3::new(0.5, 0.5, 0.5)),
         )),
     ];
+    let camera = Camera::new(
+        Vec3::new(0.0, -1.0, 0.0),
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 1.0),
+        53.13,
+        IMAGE_WIDTH as Float / IMAGE_HEIGHT as Float,
+    );
     let raytracer = raytracer::Raytracer::new(IMAGE_WIDTH, IMAGE_HEIGHT, SAMPLE_NUM);
-    let pixels = raytracer.run(objects);
+    let pixels = raytracer.run(&camera, objects);
     let mut image_buffer = Vec::new();
-    for i in 0..pixels.len() {
-        image_buffer.push((pixels[i].x.sqrt() * 256.0 ) as u8);
-        image_buffer.push((pixels[i].y.sqrt() * 256.0 ) as u8);
-        image_buffer.push((pixels[i].z.sqrt() * 256.0 ) as u8);
+    for pixel in pixels.iter() {
+        image_buffer.push((pixel.x.sqrt() * 256.0 ) as u8);
+        image_buffer.push((pixel.y.sqrt() * 256.0 ) as u8);
+        image_buffer.push((pixel.z.sqrt() * 256.0 ) as u8);
         image_buffer.push(255);
     }
     // image buffer to file
@@ -0,0 +1,485 @@
+use crate::math::{Float, Ray, Vec3};
+use crate::object::{Hittable, SurfaceInteraction};
+use crate::reflection::HitAttr;
+
+/// Axis-aligned bounding box.
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Aabb { min, max }
+    }
+
+    /// Returns the smallest box containing all the given points.
+    pub fn from_points(points: &[Vec3]) -> Self {
+        points
+            .iter()
+            .fold(Aabb::empty(), |aabb, p| aabb.union(&Aabb::new(*p, *p)))
+    }
+
+    /// A box that contains nothing. The union with any box returns the other box.
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vec3::new(Float::INFINITY, Float::INFINITY, Float::INFINITY),
+            max: Vec3::new(
+                Float::NEG_INFINITY,
+                Float::NEG_INFINITY,
+                Float::NEG_INFINITY,
+            ),
+        }
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Aabb {
+            min: Vec3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Vec3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+
+    /// Returns the box moved by the given offset.
+    pub fn translate(&self, offset: Vec3) -> Self {
+        Aabb {
+            min: self.min + offset,
+            max: self.max + offset,
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> Float {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Returns the distance range along the ray that lies inside the box, clipped to the interval of the ray.
+    /// Implementation is based on the slab method.
+    pub fn intersect(&self, ray: &Ray) -> Option<(Float, Float)> {
+        self.intersect_range(ray, ray.t_min, ray.t_max)
+    }
+
+    /// Same as intersect, but clipped to [t_min, t_max]. t_min may be negative to include the part behind the origin.
+    pub fn intersect_range(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let mut t_0 = t_min;
+        let mut t_1 = t_max;
+        for (origin, direction, min, max) in [
+            (ray.origin.x, ray.direction.x, self.min.x, self.max.x),
+            (ray.origin.y, ray.direction.y, self.min.y, self.max.y),
+            (ray.origin.z, ray.direction.z, self.min.z, self.max.z),
+        ] {
+            let inv_direction = 1.0 / direction;
+            let t_near = (min - origin) * inv_direction;
+            let t_far = (max - origin) * inv_direction;
+            // min and max ignore NaN, which occurs when the ray lies exactly on a slab.
+            t_0 = t_0.max(t_near.min(t_far));
+            t_1 = t_1.min(t_near.max(t_far));
+            if t_0 > t_1 {
+                return None;
+            }
+        }
+        Some((t_0, t_1))
+    }
+
+    fn axis(v: &Vec3, axis: usize) -> Float {
+        match axis {
+            0 => v.x,
+            1 => v.y,
+            _ => v.z,
+        }
+    }
+}
+
+enum BvhNodeKind {
+    /// Primitives `indices[first..first + count]`.
+    Leaf { first: usize, count: usize },
+    /// The left child directly follows its parent in the node array.
+    Interior { right: usize },
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    kind: BvhNodeKind,
+}
+
+// Number of buckets the centroids are binned into when evaluating the surface area heuristic.
+const SAH_BUCKETS: usize = 12;
+// Leaves larger than this are split even if the surface area heuristic suggests otherwise.
+const MAX_LEAF_SIZE: usize = 8;
+
+/// Bounding volume hierarchy over a list of primitives, built with the surface area heuristic(SAH).
+/// It only stores the indices of the primitives, so it can be used for any kind of primitive.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    /// Builds the hierarchy over primitives with the given bounding boxes.
+    pub fn new(boxes: &[Aabb]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            indices: (0..boxes.len()).collect(),
+        };
+        if !boxes.is_empty() {
+            let centroids: Vec<Vec3> = boxes.iter().map(|b| b.centroid()).collect();
+            bvh.build(boxes, &centroids, 0, boxes.len());
+        }
+        bvh
+    }
+
+    /// Recursively builds the subtree over `indices[start..end]`, and returns the index of its root.
+    fn build(&mut self, boxes: &[Aabb], centroids: &[Vec3], start: usize, end: usize) -> usize {
+        let bounds = self.indices[start..end]
+            .iter()
+            .fold(Aabb::empty(), |aabb, &i| aabb.union(&boxes[i]));
+        let node_index = self.nodes.len();
+        let count = end - start;
+        self.nodes.push(BvhNode {
+            bounds,
+            kind: BvhNodeKind::Leaf { first: start, count },
+        });
+        if count <= 1 {
+            return node_index;
+        }
+
+        let centroid_bounds = Aabb::from_points(
+            &self.indices[start..end]
+                .iter()
+                .map(|&i| centroids[i])
+                .collect::<Vec<_>>(),
+        );
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        let axis_min = Aabb::axis(&centroid_bounds.min, axis);
+        let axis_extent = Aabb::axis(&extent, axis);
+        // All centroids are at the same place, so they cannot be separated.
+        if axis_extent <= 0.0 {
+            return node_index;
+        }
+        let bucket_of = |i: usize| {
+            let offset = (Aabb::axis(&centroids[i], axis) - axis_min) / axis_extent;
+            ((offset * SAH_BUCKETS as Float) as usize).min(SAH_BUCKETS - 1)
+        };
+
+        let mut bucket_counts = [0; SAH_BUCKETS];
+        let mut bucket_bounds = [Aabb::empty(); SAH_BUCKETS];
+        for &i in &self.indices[start..end] {
+            let b = bucket_of(i);
+            bucket_counts[b] += 1;
+            bucket_bounds[b] = bucket_bounds[b].union(&boxes[i]);
+        }
+        // Cost of splitting after each bucket, relative to the cost of intersecting one primitive.
+        let mut best_cost = Float::INFINITY;
+        let mut best_split = 0;
+        for split in 0..SAH_BUCKETS - 1 {
+            let (mut left, mut right) = (Aabb::empty(), Aabb::empty());
+            let (mut left_count, mut right_count) = (0, 0);
+            for b in 0..=split {
+                left = left.union(&bucket_bounds[b]);
+                left_count += bucket_counts[b];
+            }
+            for b in split + 1..SAH_BUCKETS {
+                right = right.union(&bucket_bounds[b]);
+                right_count += bucket_counts[b];
+            }
+            let cost = 1.0
+                + (left.surface_area() * left_count as Float
+                    + right.surface_area() * right_count as Float)
+                    / bounds.surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+        if best_cost >= count as Float && count <= MAX_LEAF_SIZE {
+            return node_index;
+        }
+
+        let mut mid = start;
+        for j in start..end {
+            if bucket_of(self.indices[j]) <= best_split {
+                self.indices.swap(j, mid);
+                mid += 1;
+            }
+        }
+        if mid == start || mid == end {
+            mid = (start + end) / 2;
+        }
+        self.build(boxes, centroids, start, mid);
+        let right = self.build(boxes, centroids, mid, end);
+        self.nodes[node_index].kind = BvhNodeKind::Interior { right };
+        node_index
+    }
+
+    /// Returns the distance and the index of the closest primitive hit by the ray within its interval.
+    /// `intersect` is called with a primitive index and the distance of the closest hit so far,
+    /// and should only return a distance if the primitive is hit closer than that.
+    pub fn closest_hit<F>(&self, ray: &Ray, mut intersect: F) -> Option<(Float, usize)>
+    where
+        F: FnMut(usize, Float) -> Option<Float>,
+    {
+        let mut closest: Option<(Float, usize)> = None;
+        if self.nodes.is_empty() {
+            return closest;
+        }
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let t_max = closest.map_or(ray.t_max, |c| c.0);
+            let node = &self.nodes[node_index];
+            if node.bounds.intersect_range(ray, ray.t_min, t_max).is_none() {
+                continue;
+            }
+            match node.kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    for &i in &self.indices[first..first + count] {
+                        let t_max = closest.map_or(ray.t_max, |c| c.0);
+                        if let Some(t) = intersect(i, t_max) {
+                            if t < t_max {
+                                closest = Some((t, i));
+                            }
+                        }
+                    }
+                }
+                BvhNodeKind::Interior { right } => {
+                    let left = node_index + 1;
+                    // Visit the nearer child first, so farther subtrees are likely culled.
+                    let near = |i: usize| {
+                        self.nodes[i]
+                            .bounds
+                            .intersect_range(ray, ray.t_min, t_max)
+                            .map_or(Float::INFINITY, |(t, _)| t)
+                    };
+                    if near(left) < near(right) {
+                        stack.push(right);
+                        stack.push(left);
+                    } else {
+                        stack.push(left);
+                        stack.push(right);
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    /// Calls `visit` with the index of every primitive whose bounding box the ray passes through within its interval.
+    pub fn for_each_candidate<F>(&self, ray: &Ray, mut visit: F)
+    where
+        F: FnMut(usize),
+    {
+        self.any_hit(ray, |i| {
+            visit(i);
+            false
+        });
+    }
+
+    /// Returns whether the ray hits any primitive within its interval, and stops at the first one found.
+    /// `hit` is called with a primitive index.
+    pub fn any_hit<F>(&self, ray: &Ray, mut hit: F) -> bool
+    where
+        F: FnMut(usize) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.bounds.intersect(ray).is_none() {
+                continue;
+            }
+            match node.kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    if self.indices[first..first + count].iter().any(|&i| hit(i)) {
+                        return true;
+                    }
+                }
+                BvhNodeKind::Interior { right } => {
+                    stack.push(right);
+                    stack.push(node_index + 1);
+                }
+            }
+        }
+        false
+    }
+}
+
+/// A list of objects, with a BVH over the ones that have a bounding box.
+/// Unbounded objects(e.g. Floor) are tested one by one.
+pub struct HittableList {
+    objects: Vec<Box<dyn Hittable + Send + Sync>>,
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
+    bvh: Bvh,
+}
+
+impl HittableList {
+    pub fn new(objects: Vec<Box<dyn Hittable + Send + Sync>>) -> Self {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        let mut boxes = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            match object.bounding_box() {
+                Some(aabb) => {
+                    bounded.push(i);
+                    boxes.push(aabb);
+                }
+                None => unbounded.push(i),
+            }
+        }
+        HittableList {
+            objects,
+            bounded,
+            unbounded,
+            bvh: Bvh::new(&boxes),
+        }
+    }
+}
+
+impl Hittable for HittableList {
+    // Every object is only asked for hits closer than the closest one so far, so it can give up early.
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let mut closest: Option<SurfaceInteraction> = None;
+        let mut local = *h;
+        self.bvh.closest_hit(&h.ray, |i, t_max| {
+            local.ray.t_max = t_max;
+            let si = self.objects[self.bounded[i]].intersect(&local)?;
+            if si.t >= t_max {
+                return None;
+            }
+            closest = Some(si);
+            Some(si.t)
+        });
+        for object in self.unbounded.iter().map(|&i| &self.objects[i]) {
+            local.ray.t_max = closest.map_or(h.ray.t_max, |c| c.t);
+            if let Some(si) = object.intersect(&local) {
+                if si.t < local.ray.t_max {
+                    closest = Some(si);
+                }
+            }
+        }
+        closest
+    }
+
+    fn occluded(&self, h: &HitAttr) -> bool {
+        self.bvh
+            .any_hit(&h.ray, |i| self.objects[self.bounded[i]].occluded(h))
+            || self
+                .unbounded
+                .iter()
+                .any(|&i| self.objects[i].occluded(h))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        Some(
+            self.objects
+                .iter()
+                .filter_map(|o| o.bounding_box())
+                .fold(Aabb::empty(), |aabb, b| aabb.union(&b)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{Floor, Sphere};
+    use crate::reflection::Diffuse;
+
+    fn sphere(x: Float, radius: Float) -> Box<dyn Hittable + Send + Sync> {
+        Box::new(Sphere::new(
+            Vec3::new(x, 0.0, 0.0),
+            radius,
+            Diffuse::new(Vec3::one()),
+        ))
+    }
+
+    fn scene() -> HittableList {
+        // Overlapping spheres along the x axis, listed out of order, above a floor at z = -1.
+        HittableList::new(vec![
+            sphere(6.0, 1.0),
+            sphere(3.0, 1.5),
+            sphere(4.0, 1.0),
+            Box::new(Floor::new(-1.0, true, Diffuse::new(Vec3::one()))),
+        ])
+    }
+
+    fn ray(t_max: Float) -> HitAttr {
+        HitAttr {
+            t: 0.0,
+            ray: Ray::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0)).with_interval(0.0, t_max),
+        }
+    }
+
+    #[test]
+    fn closest_of_overlapping_objects() {
+        let scene = scene();
+        let si = scene.intersect(&ray(Float::INFINITY)).unwrap();
+        assert!((si.t - 1.5).abs() < 1e-5);
+        // Starting inside the two first spheres, the far side of the larger one is the closest.
+        let h = HitAttr {
+            t: 0.0,
+            ray: Ray::new(Vec3::new(3.5, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)),
+        };
+        assert!((scene.intersect(&h).unwrap().t - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn hits_beyond_t_max_are_ignored() {
+        let scene = scene();
+        assert!(scene.intersect(&ray(1.4)).is_none());
+        assert!((scene.intersect(&ray(1.6)).unwrap().t - 1.5).abs() < 1e-5);
+        // Downwards the floor is hit at distance 1.
+        let down = |t_max: Float| HitAttr {
+            t: 0.0,
+            ray: Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0)).with_interval(0.0, t_max),
+        };
+        assert!(scene.intersect(&down(0.9)).is_none());
+        assert!(scene.intersect(&down(1.1)).is_some());
+    }
+
+    #[test]
+    fn occluded_agrees_with_intersect() {
+        let scene = scene();
+        let directions = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(1.0, 0.3, 0.2),
+        ];
+        for direction in directions {
+            for t_max in [0.5, 1.2, 2.0, 10.0, Float::INFINITY] {
+                let h = HitAttr {
+                    t: 0.0,
+                    ray: Ray::new(Vec3::zero(), direction).with_interval(0.0, t_max),
+                };
+                assert_eq!(scene.occluded(&h), scene.intersect(&h).is_some());
+            }
+        }
+    }
+}
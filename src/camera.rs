@@ -0,0 +1,202 @@
+use crate::math::{rand, Float, Ray, Vec3, PI};
+
+/// The shape of the lens aperture. It determines the shape of out-of-focus highlights(bokeh).
+#[derive(Clone, Copy)]
+pub enum Aperture {
+    Circular,
+    /// A regular polygon inscribed in the lens disk, e.g. 6 blades for a hexagonal bokeh.
+    /// `rotation` is in degrees. Fewer than 3 blades enclose no area, and are treated as 3.
+    Polygon { blades: usize, rotation: Float },
+}
+
+impl Aperture {
+    /// Samples a point uniformly over the aperture, scaled to the unit disk. The z component is always 0.
+    fn sample(&self) -> Vec3 {
+        match *self {
+            Aperture::Circular => Vec3::random_in_unit_disk(),
+            Aperture::Polygon { blades, rotation } => {
+                let blades = blades.max(3);
+                // Pick one of the triangles fanning out from the center, then a uniform point inside it.
+                let blade = ((rand() * blades as Float) as usize).min(blades - 1);
+                let step = 2.0 * PI / blades as Float;
+                let theta_0 = rotation.to_radians() + step * blade as Float;
+                let theta_1 = theta_0 + step;
+                let r_1 = rand().sqrt();
+                let r_2 = rand();
+                let a = r_1 * (1.0 - r_2);
+                let b = r_1 * r_2;
+                Vec3::new(
+                    a * theta_0.cos() + b * theta_1.cos(),
+                    a * theta_0.sin() + b * theta_1.sin(),
+                    0.0,
+                )
+            }
+        }
+    }
+}
+
+/// How points on the image are mapped to ray directions.
+#[derive(Clone, Copy)]
+pub enum Projection {
+    /// Standard pinhole projection using the vertical field of view of the camera.
+    Perspective,
+    /// Parallel rays along the viewing direction. `height` is the height of the view in world units.
+    Orthographic { height: Float },
+    /// Equidistant fisheye projection. `fov` is the field of view in degrees across the image height.
+    /// Pixels outside the image circle are not rendered.
+    Fisheye { fov: Float },
+    /// 360° longitude/latitude panorama, centered on the viewing direction.
+    Equirectangular,
+}
+
+/// A thin-lens camera looking from `look_from` towards `look_at`.
+/// With the default zero aperture it behaves as a pinhole camera, so everything is in focus.
+#[derive(Clone, Copy)]
+pub struct Camera {
+    origin: Vec3,
+    // Orthonormal basis of the camera. w points backwards, u to the right and v upwards.
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    // Size of the image plane at distance 1 from the origin.
+    viewport_width: Float,
+    viewport_height: Float,
+    lens_radius: Float,
+    focus_dist: Float,
+    aperture: Aperture,
+    projection: Projection,
+    shutter_open: Float,
+    shutter_close: Float,
+}
+
+impl Camera {
+    /// Constructs a new pinhole camera.
+    /// `vup` is the up direction of the world, and `vfov` is the vertical field of view in degrees.
+    /// `aspect_ratio` is the image width divided by the image height.
+    pub fn new(
+        look_from: Vec3,
+        look_at: Vec3,
+        vup: Vec3,
+        vfov: Float,
+        aspect_ratio: Float,
+    ) -> Self {
+        let viewport_height = 2.0 * (vfov.to_radians() * 0.5).tan();
+        let viewport_width = aspect_ratio * viewport_height;
+
+        let w = (look_from - look_at).normalize();
+        let u = vup.cross(&w).normalize();
+        let v = w.cross(&u);
+
+        Camera {
+            origin: look_from,
+            u,
+            v,
+            w,
+            viewport_width,
+            viewport_height,
+            lens_radius: 0.0,
+            focus_dist: 1.0,
+            aperture: Aperture::Circular,
+            projection: Projection::Perspective,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        }
+    }
+
+    /// Turns the camera into a thin lens with the given aperture radius.
+    /// Objects at `focus_dist` from the camera are in perfect focus.
+    pub fn with_lens(self, aperture_radius: Float, focus_dist: Float) -> Self {
+        Camera {
+            lens_radius: aperture_radius,
+            focus_dist,
+            ..self
+        }
+    }
+
+    /// Sets the shape of the aperture. Only visible when the lens radius is not zero.
+    pub fn with_aperture(self, aperture: Aperture) -> Self {
+        Camera { aperture, ..self }
+    }
+
+    /// Sets the projection of the camera. The lens only affects perspective and orthographic projections.
+    pub fn with_projection(self, projection: Projection) -> Self {
+        Camera { projection, ..self }
+    }
+
+    /// Sets the interval the shutter stays open. Rays are shot at uniformly distributed times within it,
+    /// which blurs objects moving during the interval.
+    pub fn with_shutter(self, shutter_open: Float, shutter_close: Float) -> Self {
+        Camera {
+            shutter_open,
+            shutter_close,
+            ..self
+        }
+    }
+
+    /// Returns the ray through the point (s, t) of the image, where both coordinates are in [0, 1]
+    /// and (0, 0) is the lower left corner. The ray origin is sampled over the lens,
+    /// and the time of the ray over the shutter interval.
+    /// Returns None if the point is not covered by the projection.
+    pub fn get_ray(&self, s: Float, t: Float) -> Option<Ray> {
+        let time = self.shutter_open + rand() * (self.shutter_close - self.shutter_open);
+        self.project(s, t).map(|ray| ray.with_time(time))
+    }
+
+    fn project(&self, s: Float, t: Float) -> Option<Ray> {
+        let aspect_ratio = self.viewport_width / self.viewport_height;
+        match self.projection {
+            Projection::Perspective => {
+                let target = self.origin
+                    + (self.u * ((s - 0.5) * self.viewport_width)
+                        + self.v * ((t - 0.5) * self.viewport_height)
+                        - self.w)
+                        * self.focus_dist;
+                Some(self.lens_ray(self.origin, target))
+            }
+            Projection::Orthographic { height } => {
+                let center = self.origin
+                    + self.u * ((s - 0.5) * height * aspect_ratio)
+                    + self.v * ((t - 0.5) * height);
+                Some(self.lens_ray(center, center - self.w * self.focus_dist))
+            }
+            Projection::Fisheye { fov } => {
+                let x = (2.0 * s - 1.0) * aspect_ratio;
+                let y = 2.0 * t - 1.0;
+                let r = (x * x + y * y).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                let theta = r * fov.to_radians() * 0.5;
+                let phi = y.atan2(x);
+                Some(Ray::new(
+                    self.origin,
+                    self.direction(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()),
+                ))
+            }
+            Projection::Equirectangular => {
+                let longitude = (s - 0.5) * 2.0 * PI;
+                let latitude = (t - 0.5) * PI;
+                Some(Ray::new(
+                    self.origin,
+                    self.direction(
+                        latitude.cos() * longitude.sin(),
+                        latitude.sin(),
+                        latitude.cos() * longitude.cos(),
+                    ),
+                ))
+            }
+        }
+    }
+
+    /// Converts a direction in camera space (right, up, forward) to world space.
+    fn direction(&self, right: Float, up: Float, forward: Float) -> Vec3 {
+        self.u * right + self.v * up - self.w * forward
+    }
+
+    /// Returns the ray from a point sampled on the lens around `center` towards the in-focus point `target`.
+    fn lens_ray(&self, center: Vec3, target: Vec3) -> Ray {
+        let lens = self.aperture.sample() * self.lens_radius;
+        let origin = center + self.u * lens.x + self.v * lens.y;
+        Ray::new(origin, target - origin)
+    }
+}
@@ -0,0 +1,194 @@
+use crate::bvh::Aabb;
+use crate::math::{self, Float, Ray, Vec3};
+use crate::object::{pair_crossings, Hittable, SurfaceInteraction};
+use crate::reflection::HitAttr;
+
+/// How the two operands of a Csg are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsgOperation {
+    /// Inside either operand.
+    Union,
+    /// Inside both operands.
+    Intersection,
+    /// Inside the first operand, but not inside the second.
+    Difference,
+}
+
+impl CsgOperation {
+    fn contains(&self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            CsgOperation::Union => in_a || in_b,
+            CsgOperation::Intersection => in_a && in_b,
+            CsgOperation::Difference => in_a && !in_b,
+        }
+    }
+}
+
+/// A point where the ray crosses the surface of the combined solid, and the operand the surface belongs to.
+#[derive(Clone, Copy)]
+struct Boundary {
+    t: Float,
+    from_b: bool,
+    entering: bool,
+}
+
+/// Constructive solid geometry: combines two closed objects into a new solid, e.g. a lens as the intersection of two spheres.
+/// The surface keeps the reflection of the operand it comes from.
+/// Operands without an inside(see Hittable::get_intervals) are treated as empty.
+pub struct Csg<A: Hittable, B: Hittable> {
+    a: A,
+    b: B,
+    operation: CsgOperation,
+}
+
+impl<A, B> Csg<A, B>
+where
+    A: Hittable,
+    B: Hittable,
+{
+    pub fn new(a: A, b: B, operation: CsgOperation) -> Self {
+        Csg { a, b, operation }
+    }
+
+    pub fn union(a: A, b: B) -> Self {
+        Self::new(a, b, CsgOperation::Union)
+    }
+
+    pub fn intersection(a: A, b: B) -> Self {
+        Self::new(a, b, CsgOperation::Intersection)
+    }
+
+    /// Cuts `b` out of `a`.
+    pub fn difference(a: A, b: B) -> Self {
+        Self::new(a, b, CsgOperation::Difference)
+    }
+
+    /// Returns the surface crossings of the combined solid along the whole line through the ray, in ascending order.
+    /// They alternate between entering and leaving the solid, starting with an entry.
+    fn boundaries(&self, h: &HitAttr) -> Vec<Boundary> {
+        let mut events: Vec<Boundary> = Vec::new();
+        for (intervals, from_b) in [
+            (self.a.get_intervals(h), false),
+            (self.b.get_intervals(h), true),
+        ] {
+            for (t_in, t_out) in intervals {
+                events.push(Boundary {
+                    t: t_in,
+                    from_b,
+                    entering: true,
+                });
+                events.push(Boundary {
+                    t: t_out,
+                    from_b,
+                    entering: false,
+                });
+            }
+        }
+        events.sort_by(|x, y| x.t.total_cmp(&y.t));
+
+        // Sweep along the ray, and keep the events where the combined solid is entered or left.
+        // The intervals of an operand may overlap, so the number of intervals the ray is in is counted.
+        let (mut depth_a, mut depth_b, mut inside) = (0, 0, false);
+        let mut boundaries: Vec<Boundary> = Vec::new();
+        for event in events {
+            let depth = if event.from_b {
+                &mut depth_b
+            } else {
+                &mut depth_a
+            };
+            if event.entering {
+                *depth += 1;
+            } else {
+                *depth -= 1;
+            }
+            if self.operation.contains(depth_a > 0, depth_b > 0) != inside {
+                inside = !inside;
+                // Leaving and entering again at the same distance(e.g. touching operands) is no boundary.
+                match boundaries.last() {
+                    Some(last) if last.t == event.t => {
+                        boundaries.pop();
+                    }
+                    _ => boundaries.push(Boundary {
+                        entering: inside,
+                        ..event
+                    }),
+                }
+            }
+        }
+        boundaries
+    }
+
+    /// Returns a ray starting just in front of p, whose interval only admits hits within EPSILON of p.
+    /// Other surfaces of the operand close to p(e.g. the inside of a thin shell) are not hit.
+    fn ray_to(h: &HitAttr, p: Vec3) -> HitAttr {
+        let mut local = *h;
+        local.ray = Ray {
+            origin: p - h.ray.direction * (math::EPSILON * 10.0),
+            ..h.ray
+        }
+        .with_interval(math::EPSILON * 9.0, math::EPSILON * 11.0);
+        local
+    }
+}
+
+impl<A, B> Hittable for Csg<A, B>
+where
+    A: Hittable,
+    B: Hittable,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        // The surface at a boundary is found again on its operand, to get the full interaction.
+        let (boundary, mut si) = self
+            .boundaries(h)
+            .into_iter()
+            .filter(|b| h.ray.contains(b.t))
+            .find_map(|boundary| {
+                let local = Self::ray_to(h, h.ray.at(boundary.t));
+                let si = if boundary.from_b {
+                    self.b.intersect(&local)
+                } else {
+                    self.a.intersect(&local)
+                };
+                Some((boundary, si?))
+            })?;
+        let p = h.ray.at(boundary.t);
+        si.t = boundary.t;
+        si.p = p;
+        // The inside of the cut-out operand becomes the outside, so its surface is flipped.
+        if boundary.from_b && self.operation == CsgOperation::Difference {
+            si = si.flip();
+        }
+        Some(si)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        match self.operation {
+            CsgOperation::Union => Some(self.a.bounding_box()?.union(&self.b.bounding_box()?)),
+            CsgOperation::Intersection => match (self.a.bounding_box(), self.b.bounding_box()) {
+                (Some(a), Some(b)) => Some(Aabb::new(
+                    Vec3::new(
+                        a.min.x.max(b.min.x),
+                        a.min.y.max(b.min.y),
+                        a.min.z.max(b.min.z),
+                    ),
+                    Vec3::new(
+                        a.max.x.min(b.max.x),
+                        a.max.y.min(b.max.y),
+                        a.max.z.min(b.max.z),
+                    ),
+                )),
+                (a, b) => a.or(b),
+            },
+            CsgOperation::Difference => self.a.bounding_box(),
+        }
+    }
+
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        pair_crossings(
+            self.boundaries(h)
+                .into_iter()
+                .map(|b| (b.t, b.entering))
+                .collect(),
+        )
+    }
+}
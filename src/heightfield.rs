@@ -0,0 +1,241 @@
+use crate::bvh::Aabb;
+use crate::math::{Float, Vec3};
+use crate::mesh::{intersect_triangle, triangle_tangents};
+use crate::object::{facing, Hittable, SurfaceInteraction};
+use crate::reflection::{HitAttr, Reflection};
+use image::error::{ImageError, ParameterError, ParameterErrorKind};
+use std::path::Path;
+
+/// A triangle of the terrain, as the (i, j) indices of its vertices.
+type Triangle = [(usize, usize); 3];
+
+/// Terrain given by a grid of height samples, and a given reflection.
+/// Every grid cell is split into two triangles, and rays walk through the cells with a grid DDA
+/// instead of testing all triangles. Normals are interpolated between the vertices, so the terrain looks smooth.
+/// The surface is two-sided, so the normal always faces the incoming ray.
+pub struct Heightfield<R: Reflection> {
+    heights: Vec<Float>,
+    normals: Vec<Vec3>,
+    // Number of samples along x and y.
+    nx: usize,
+    ny: usize,
+    origin: Vec3,
+    size: Vec3,
+    bounds: Aabb,
+    reflection: R,
+}
+
+impl<R> Heightfield<R>
+where
+    R: Reflection,
+{
+    /// Constructs a heightfield from `nx * ny` samples in [0, 1], stored row by row starting at the lowest y.
+    /// The terrain spans `size.x` and `size.y` from `origin`, and a sample of 1 lies `size.z` above the origin.
+    /// Panics if there are less than 2 samples along an axis.
+    pub fn new(
+        heights: Vec<Float>,
+        nx: usize,
+        ny: usize,
+        origin: Vec3,
+        size: Vec3,
+        reflection: R,
+    ) -> Self {
+        assert!(nx >= 2 && ny >= 2, "a heightfield needs at least 2x2 samples");
+        assert_eq!(heights.len(), nx * ny, "expected {nx}x{ny} height samples");
+        let (min, max) = heights
+            .iter()
+            .fold((Float::INFINITY, Float::NEG_INFINITY), |(min, max), &h| {
+                (min.min(h), max.max(h))
+            });
+        let mut heightfield = Heightfield {
+            heights,
+            normals: Vec::new(),
+            nx,
+            ny,
+            origin,
+            size,
+            bounds: Aabb::new(
+                Vec3::new(origin.x, origin.y, origin.z + min * size.z),
+                Vec3::new(origin.x + size.x, origin.y + size.y, origin.z + max * size.z),
+            ),
+            reflection,
+        };
+        heightfield.normals = (0..nx * ny)
+            .map(|k| heightfield.vertex_normal(k % nx, k / nx))
+            .collect();
+        heightfield
+    }
+
+    /// Loads the samples from a grayscale image, where white is the highest point.
+    /// The top row of the image is the far end of the terrain along y.
+    /// Fails if the image is smaller than 2x2 pixels.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        origin: Vec3,
+        size: Vec3,
+        reflection: R,
+    ) -> Result<Self, ImageError> {
+        let image = image::open(path)?.into_luma16();
+        let (nx, ny) = (image.width() as usize, image.height() as usize);
+        if nx < 2 || ny < 2 {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::Generic(format!(
+                    "a heightfield needs at least 2x2 samples, found {nx}x{ny}"
+                )),
+            )));
+        }
+        let mut heights = Vec::with_capacity(nx * ny);
+        for j in 0..ny {
+            for i in 0..nx {
+                let pixel = image.get_pixel(i as u32, (ny - 1 - j) as u32);
+                heights.push(pixel.0[0] as Float / u16::MAX as Float);
+            }
+        }
+        Ok(Self::new(heights, nx, ny, origin, size, reflection))
+    }
+
+    fn cell_size(&self) -> (Float, Float) {
+        (
+            self.size.x / (self.nx - 1) as Float,
+            self.size.y / (self.ny - 1) as Float,
+        )
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> Vec3 {
+        let (cell_x, cell_y) = self.cell_size();
+        Vec3::new(
+            self.origin.x + i as Float * cell_x,
+            self.origin.y + j as Float * cell_y,
+            self.origin.z + self.heights[j * self.nx + i] * self.size.z,
+        )
+    }
+
+    /// Normal at a sample, from the slope between its neighbours.
+    fn vertex_normal(&self, i: usize, j: usize) -> Vec3 {
+        let (i_0, i_1) = (i.saturating_sub(1), (i + 1).min(self.nx - 1));
+        let (j_0, j_1) = (j.saturating_sub(1), (j + 1).min(self.ny - 1));
+        let dx = self.vertex(i_1, j) - self.vertex(i_0, j);
+        let dy = self.vertex(i, j_1) - self.vertex(i, j_0);
+        dx.cross(&dy).normalize()
+    }
+
+    /// Returns the two triangles of a cell. Both are wound upwards.
+    fn cell_triangles(i: usize, j: usize) -> [Triangle; 2] {
+        [
+            [(i, j), (i + 1, j), (i + 1, j + 1)],
+            [(i, j), (i + 1, j + 1), (i, j + 1)],
+        ]
+    }
+
+    /// Returns the distance, the triangle and the barycentric coordinates of the closest hit in front of the ray within the cell.
+    fn intersect_cell(
+        &self,
+        i: usize,
+        j: usize,
+        h: &HitAttr,
+    ) -> Option<(Float, Triangle, Float, Float)> {
+        Self::cell_triangles(i, j)
+            .into_iter()
+            .filter_map(|triangle| {
+                let corners = triangle.map(|(i, j)| self.vertex(i, j));
+                intersect_triangle(corners, &h.ray).map(|(t, b_1, b_2)| (t, triangle, b_1, b_2))
+            })
+            .filter(|&(t, _, _, _)| h.ray.contains(t))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    }
+
+    /// Constructs the interaction at a hit on the given triangle.
+    fn interaction(
+        &self,
+        t: Float,
+        triangle: Triangle,
+        b_1: Float,
+        b_2: Float,
+        h: &HitAttr,
+    ) -> SurfaceInteraction<'_> {
+        let corners = triangle.map(|(i, j)| self.vertex(i, j));
+        let normals = triangle.map(|(i, j)| self.normals[j * self.nx + i]);
+        // The terrain is parametrized by the position in the grid.
+        let uvs = triangle.map(|(i, j)| {
+            [
+                i as Float / (self.nx - 1) as Float,
+                j as Float / (self.ny - 1) as Float,
+            ]
+        });
+        let b_0 = 1.0 - b_1 - b_2;
+        let uv = [
+            uvs[0][0] * b_0 + uvs[1][0] * b_1 + uvs[2][0] * b_2,
+            uvs[0][1] * b_0 + uvs[1][1] * b_1 + uvs[2][1] * b_2,
+        ];
+        let face_normal = (corners[1] - corners[0])
+            .cross(&(corners[2] - corners[0]))
+            .normalize();
+        let shading_normal = (normals[0] * b_0 + normals[1] * b_1 + normals[2] * b_2).normalize();
+        SurfaceInteraction::new(
+            t,
+            h,
+            facing(face_normal, h),
+            uv,
+            triangle_tangents(corners, uvs),
+            &self.reflection,
+        )
+        .with_shading_normal(facing(shading_normal, h))
+        .with_front_side(face_normal, h)
+    }
+}
+
+impl<R> Hittable for Heightfield<R>
+where
+    R: Reflection,
+{
+    // Implementation is based on Amanatides and Woo, "A Fast Voxel Traversal Algorithm for Ray Tracing".
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let (t_0, t_1) = self.bounds.intersect(&h.ray)?;
+        let (cell_x, cell_y) = self.cell_size();
+        let (cells_x, cells_y) = (self.nx as isize - 1, self.ny as isize - 1);
+        let entry = h.ray.at(t_0);
+        let mut i = (((entry.x - self.origin.x) / cell_x).floor() as isize).clamp(0, cells_x - 1);
+        let mut j = (((entry.y - self.origin.y) / cell_y).floor() as isize).clamp(0, cells_y - 1);
+
+        // Per axis: the cell step, the distance to the next cell boundary, and the distance between boundaries.
+        let axis = |direction: Float, origin: Float, grid_origin: Float, cell: Float, index: isize| {
+            if direction > 0.0 {
+                let boundary = grid_origin + (index + 1) as Float * cell;
+                (1, (boundary - origin) / direction, cell / direction)
+            } else if direction < 0.0 {
+                let boundary = grid_origin + index as Float * cell;
+                (-1, (boundary - origin) / direction, -cell / direction)
+            } else {
+                (0, Float::INFINITY, Float::INFINITY)
+            }
+        };
+        let (step_i, mut t_max_i, t_delta_i) =
+            axis(h.ray.direction.x, h.ray.origin.x, self.origin.x, cell_x, i);
+        let (step_j, mut t_max_j, t_delta_j) =
+            axis(h.ray.direction.y, h.ray.origin.y, self.origin.y, cell_y, j);
+
+        loop {
+            // Cells are visited in order along the ray, so the first hit is the closest.
+            if let Some((t, triangle, b_1, b_2)) = self.intersect_cell(i as usize, j as usize, h) {
+                return Some(self.interaction(t, triangle, b_1, b_2, h));
+            }
+            if t_max_i.min(t_max_j) > t_1 {
+                return None;
+            }
+            if t_max_i < t_max_j {
+                i += step_i;
+                t_max_i += t_delta_i;
+            } else {
+                j += step_j;
+                t_max_j += t_delta_j;
+            }
+            if i < 0 || i >= cells_x || j < 0 || j >= cells_y {
+                return None;
+            }
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+}
@@ -1,5 +1,14 @@
 pub mod app;
+pub mod bvh;
+pub mod camera;
+pub mod csg;
+pub mod heightfield;
 pub mod math;
+pub mod medium;
+pub mod mesh;
+pub mod microfacet;
 pub mod object;
 pub mod raytracer;
-pub mod reflection;
\ No newline at end of file
+pub mod reflection;
+pub mod sdf;
+pub mod texture;
\ No newline at end of file
@@ -1,4 +1,3 @@
-use eframe;
 use rustracer::app;
 
 
@@ -1,6 +1,7 @@
 pub type Float = f32;
 
 pub use fastrand::f32 as rand;
+pub use std::f32::consts::PI;
 
 pub const SKY_COLOR: (Float, Float, Float) = (0.8, 0.8, 1.0);
 // Used to prevent shadow acne.
@@ -115,14 +116,25 @@ impl Vec3 {
         }
     }
 
-    pub fn random_diffusion(&self) -> Self {
+    /// Returns a uniformly distributed point inside the unit disk on the xy plane.
+    pub fn random_in_unit_disk() -> Self {
         loop {
-            let v = Self::random_in_unit_sphere();
-            if v.dot(&self) > 0.0 {
+            let v = Vec3::new(rand() * 2.0 - 1.0, rand() * 2.0 - 1.0, 0.0);
+            if v.mag() < 1.0 {
                 return v;
             }
         }
     }
+
+    /// Returns a random direction on the hemisphere around the z axis, with a density of cos / pi,
+    /// where cos is the cosine to the z axis. Points on the unit disk are projected up onto the hemisphere.
+    /// Use an Onb to move it around a normal.
+    pub fn random_cosine_direction() -> Self {
+        let r_2 = rand();
+        let phi = 2.0 * PI * rand();
+        let r = r_2.sqrt();
+        Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - r_2).sqrt())
+    }
 }
 
 impl std::ops::Neg for Vec3 {
@@ -177,11 +189,303 @@ impl std::ops::Mul<Float> for Vec3 {
     }
 }
 
+/// Orthonormal basis. Used to move vectors into a local frame where w is the z axis.
+#[derive(Debug, Clone, Copy)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    /// Builds a basis around the given direction.
+    /// Implementation is based on Duff et al., "Building an Orthonormal Basis, Revisited".
+    pub fn from_w(w: Vec3) -> Self {
+        let w = w.normalize();
+        let sign = (1.0 as Float).copysign(w.z);
+        let a = -1.0 / (sign + w.z);
+        let b = w.x * w.y * a;
+        Onb {
+            u: Vec3::new(1.0 + sign * w.x * w.x * a, sign * b, -sign * w.x),
+            v: Vec3::new(b, sign + w.y * w.y * a, -w.y),
+            w,
+        }
+    }
+
+    pub fn to_local(&self, a: Vec3) -> Vec3 {
+        Vec3::new(a.dot(&self.u), a.dot(&self.v), a.dot(&self.w))
+    }
+
+    pub fn to_world(&self, a: Vec3) -> Vec3 {
+        self.u * a.x + self.v * a.y + self.w * a.z
+    }
+}
+
+/// Returns the real roots of the polynomial `sum(coefficients[i] * x^i)` within [lo, hi] in ascending order.
+/// The roots of the derivative split the range into monotonic intervals, and each sign change is refined by bisection.
+/// Roots where the polynomial only touches zero are not found.
+pub fn polynomial_roots(coefficients: &[f64], lo: f64, hi: f64) -> Vec<f64> {
+    let degree = match coefficients.iter().rposition(|&c| c != 0.0) {
+        Some(degree) => degree,
+        None => return Vec::new(),
+    };
+    let c = &coefficients[..=degree];
+    if degree == 0 {
+        return Vec::new();
+    }
+    if degree == 1 {
+        let x = -c[0] / c[1];
+        return if lo <= x && x <= hi { vec![x] } else { Vec::new() };
+    }
+
+    let derivative: Vec<f64> = c
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, a)| a * i as f64)
+        .collect();
+    let mut bounds = vec![lo];
+    bounds.extend(polynomial_roots(&derivative, lo, hi));
+    bounds.push(hi);
+
+    let eval = |x: f64| c.iter().rev().fold(0.0, |acc, a| acc * x + a);
+    let mut roots = Vec::new();
+    for interval in bounds.windows(2) {
+        let (mut a, mut b) = (interval[0], interval[1]);
+        let mut f_a = eval(a);
+        if f_a == 0.0 {
+            if roots.last() != Some(&a) {
+                roots.push(a);
+            }
+            continue;
+        }
+        let f_b = eval(b);
+        if f_b == 0.0 {
+            // The next interval starts at b, and does not add it again.
+            roots.push(b);
+            continue;
+        }
+        if (f_a < 0.0) == (f_b < 0.0) {
+            continue;
+        }
+        for _ in 0..64 {
+            let m = 0.5 * (a + b);
+            let f_m = eval(m);
+            if (f_m < 0.0) == (f_a < 0.0) {
+                a = m;
+                f_a = f_m;
+            } else {
+                b = m;
+            }
+        }
+        roots.push(0.5 * (a + b));
+    }
+    roots
+}
+
+/// Row-major 4x4 matrix acting on column vectors.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Mat4 {
+    pub m: [[Float; 4]; 4],
+}
+
+impl Mat4 {
+    pub fn identity() -> Self {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        Mat4 { m }
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.m[j][i];
+            }
+        }
+        Mat4 { m }
+    }
+
+    /// Returns the inverse matrix, or None if the matrix is singular or nearly singular.
+    /// Implementation is based on Gauss-Jordan elimination with partial pivoting.
+    pub fn inverse(&self) -> Option<Self> {
+        let mut a = self.m;
+        let mut inv = Mat4::identity().m;
+        // Pivots are compared to the largest entry, since rounding leaves a relative error in them.
+        let largest = a
+            .iter()
+            .flatten()
+            .fold(0.0, |max: Float, x| max.max(x.abs()));
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+            if a[pivot][col].abs() <= 1e-6 * largest {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+            let scale = 1.0 / a[col][col];
+            for j in 0..4 {
+                a[col][j] *= scale;
+                inv[col][j] *= scale;
+            }
+            for row in 0..4 {
+                if row != col {
+                    let factor = a[row][col];
+                    for j in 0..4 {
+                        a[row][j] -= factor * a[col][j];
+                        inv[row][j] -= factor * inv[col][j];
+                    }
+                }
+            }
+        }
+        Some(Mat4 { m: inv })
+    }
+}
+
+impl std::ops::Mul for Mat4 {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[i][k] * other.m[k][j]).sum();
+            }
+        }
+        Mat4 { m }
+    }
+}
+
+/// An affine transform together with its inverse, so neither has to be recomputed while rendering.
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    pub matrix: Mat4,
+    pub inverse: Mat4,
+}
+
+impl Transform {
+    /// Panics if the matrix is not invertible, e.g. a scale by zero.
+    pub fn new(matrix: Mat4) -> Self {
+        Transform {
+            matrix,
+            inverse: matrix.inverse().expect("transform matrix is not invertible"),
+        }
+    }
+
+    pub fn identity() -> Self {
+        Transform {
+            matrix: Mat4::identity(),
+            inverse: Mat4::identity(),
+        }
+    }
+
+    pub fn translate(offset: Vec3) -> Self {
+        let mut matrix = Mat4::identity();
+        matrix.m[0][3] = offset.x;
+        matrix.m[1][3] = offset.y;
+        matrix.m[2][3] = offset.z;
+        let mut inverse = Mat4::identity();
+        inverse.m[0][3] = -offset.x;
+        inverse.m[1][3] = -offset.y;
+        inverse.m[2][3] = -offset.z;
+        Transform { matrix, inverse }
+    }
+
+    /// Panics if a factor is zero, like Transform::new.
+    pub fn scale(factors: Vec3) -> Self {
+        assert!(
+            factors.x != 0.0 && factors.y != 0.0 && factors.z != 0.0,
+            "transform matrix is not invertible"
+        );
+        let mut matrix = Mat4::identity();
+        matrix.m[0][0] = factors.x;
+        matrix.m[1][1] = factors.y;
+        matrix.m[2][2] = factors.z;
+        let mut inverse = Mat4::identity();
+        inverse.m[0][0] = 1.0 / factors.x;
+        inverse.m[1][1] = 1.0 / factors.y;
+        inverse.m[2][2] = 1.0 / factors.z;
+        Transform { matrix, inverse }
+    }
+
+    /// Counter-clockwise rotation around the given axis, in degrees.
+    /// Implementation is based on Rodrigues' rotation formula.
+    pub fn rotate(axis: Vec3, degrees: Float) -> Self {
+        let a = axis.normalize();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut matrix = Mat4::identity();
+        matrix.m[0][0] = cos + a.x * a.x * (1.0 - cos);
+        matrix.m[0][1] = a.x * a.y * (1.0 - cos) - a.z * sin;
+        matrix.m[0][2] = a.x * a.z * (1.0 - cos) + a.y * sin;
+        matrix.m[1][0] = a.y * a.x * (1.0 - cos) + a.z * sin;
+        matrix.m[1][1] = cos + a.y * a.y * (1.0 - cos);
+        matrix.m[1][2] = a.y * a.z * (1.0 - cos) - a.x * sin;
+        matrix.m[2][0] = a.z * a.x * (1.0 - cos) - a.y * sin;
+        matrix.m[2][1] = a.z * a.y * (1.0 - cos) + a.x * sin;
+        matrix.m[2][2] = cos + a.z * a.z * (1.0 - cos);
+        // The inverse of a rotation is its transpose.
+        Transform {
+            matrix,
+            inverse: matrix.transpose(),
+        }
+    }
+
+    /// Returns the transform that applies this transform first, and then `other`.
+    pub fn then(&self, other: &Transform) -> Self {
+        Transform {
+            matrix: other.matrix * self.matrix,
+            inverse: self.inverse * other.inverse,
+        }
+    }
+
+    pub fn inverse(&self) -> Self {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        let m = &self.matrix.m;
+        Vec3::new(
+            m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+        )
+    }
+
+    /// Transforms a direction, so the translation is ignored. The result is not normalized.
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.matrix.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    /// Transforms a surface normal with the inverse transpose, so it stays perpendicular to the surface.
+    /// The result is not normalized.
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        let m = &self.inverse.m;
+        Vec3::new(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        )
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
-    pub color: Vec3,
+    /// The moment within the shutter interval the ray was shot at. Used for motion blur.
+    pub time: Float,
+    /// Only hits within (t_min, t_max) along the ray count.
+    pub t_min: Float,
+    pub t_max: Float,
 }
 
 impl Ray {
@@ -189,11 +493,118 @@ impl Ray {
         Ray {
             origin,
             direction: direction.normalize(),
-            color: Vec3::sky_color(),
+            time: 0.0,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+
+    pub fn with_time(self, time: Float) -> Self {
+        Ray { time, ..self }
+    }
+
+    pub fn with_interval(self, t_min: Float, t_max: Float) -> Self {
+        Ray {
+            t_min,
+            t_max,
+            ..self
         }
     }
 
+    /// Whether a hit at distance t lies within the interval of the ray.
+    pub fn contains(&self, t: Float) -> bool {
+        self.t_min < t && t < self.t_max
+    }
+
     pub fn at(&self, t: Float) -> Vec3 {
         self.origin + self.direction * t
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Expands (x - r_0)(x - r_1)... into coefficients in ascending order of degree.
+    fn from_roots(roots: &[f64]) -> Vec<f64> {
+        roots.iter().fold(vec![1.0], |c, &r| {
+            let mut next = vec![0.0; c.len() + 1];
+            for (i, a) in c.iter().enumerate() {
+                next[i] -= r * a;
+                next[i + 1] += a;
+            }
+            next
+        })
+    }
+
+    fn assert_roots(found: &[f64], expected: &[f64]) {
+        let near = |(a, b): (&f64, &f64)| (a - b).abs() < 1e-9;
+        let close = found.len() == expected.len() && found.iter().zip(expected).all(near);
+        assert!(close, "found {found:?}, expected {expected:?}");
+    }
+
+    #[test]
+    fn quartic_roots() {
+        let roots = [-3.0, -0.5, 1.0, 2.5];
+        assert_roots(&polynomial_roots(&from_roots(&roots), -10.0, 10.0), &roots);
+        // Only the roots within the range are returned, including roots on its ends.
+        assert_roots(&polynomial_roots(&from_roots(&roots), 0.0, 2.0), &[1.0]);
+        assert_roots(&polynomial_roots(&from_roots(&roots), -3.0, 1.0), &roots[..3]);
+        assert_roots(&polynomial_roots(&from_roots(&roots), 1.0, 2.5), &roots[2..]);
+        // (x^2 + 1)(x - 1)(x - 4) has two real roots.
+        let coefficients = [4.0, -5.0, 5.0, -5.0, 1.0];
+        assert_roots(&polynomial_roots(&coefficients, -10.0, 10.0), &[1.0, 4.0]);
+    }
+
+    #[test]
+    fn quartic_without_real_roots() {
+        // (x^2 + 1)(x^2 + 4)
+        assert!(polynomial_roots(&[4.0, 0.0, 5.0, 0.0, 1.0], -10.0, 10.0).is_empty());
+    }
+
+    fn assert_identity(m: Mat4) {
+        let identity = Mat4::identity();
+        for (row, expected) in m.m.iter().zip(identity.m) {
+            for (a, b) in row.iter().zip(expected) {
+                assert!((a - b).abs() < 1e-5, "{m:?} is not the identity");
+            }
+        }
+    }
+
+    #[test]
+    fn matrix_inverse() {
+        let m = Mat4 {
+            m: [
+                [2.0, 0.0, 1.0, 3.0],
+                [0.0, 0.0, 4.0, -1.0],
+                [1.0, 5.0, 0.0, 2.0],
+                [0.0, 1.0, 0.0, 1.0],
+            ],
+        };
+        let inverse = m.inverse().unwrap();
+        assert_identity(m * inverse);
+        assert_identity(inverse * m);
+        let transform = Transform::rotate(Vec3::new(1.0, 2.0, 3.0), 40.0)
+            .then(&Transform::scale(Vec3::new(2.0, 0.5, 3.0)))
+            .then(&Transform::translate(Vec3::new(1.0, -2.0, 0.5)));
+        assert_identity(transform.matrix * transform.matrix.inverse().unwrap());
+    }
+
+    #[test]
+    fn singular_matrix() {
+        let mut m = Mat4::identity();
+        m.m[2] = [1.0, 2.0, 0.0, 0.0];
+        m.m[3] = [2.0, 4.0, 0.0, 0.0];
+        assert!(m.inverse().is_none());
+        // Singular, but rounding leaves small non-zero pivots.
+        let m = Mat4 {
+            m: [
+                [0.1, 0.2, 0.3, 0.0],
+                [0.4, 0.5, 0.6, 0.0],
+                [0.7, 0.8, 0.9, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        };
+        assert!(m.inverse().is_none());
+    }
+}
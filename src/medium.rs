@@ -0,0 +1,409 @@
+use crate::bvh::Aabb;
+use crate::math::{rand, Float, Onb, Ray, Vec3, PI};
+use crate::object::{tangent_frame, Hittable, SurfaceInteraction};
+use crate::reflection::{BsdfSample, HitAttr, Reflection};
+use std::fmt;
+use std::path::Path;
+
+/// Henyey-Greenstein phase function, which scatters light inside a volume.
+/// `g` in (-1, 1) is the mean cosine of the scattering angle: positive values scatter forwards,
+/// negative values backwards, and 0 scatters uniformly in all directions.
+/// The color is the fraction of light that survives each scattering event.
+pub struct HenyeyGreenstein {
+    g: Float,
+    color: Vec3,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: Float, color: Vec3) -> Self {
+        Self { g, color }
+    }
+
+    /// Samples the cosine of the angle between the incoming and the scattered direction.
+    fn sample_cos_theta(&self) -> Float {
+        let xi = rand();
+        if self.g.abs() < 1e-3 {
+            return 1.0 - 2.0 * xi;
+        }
+        let g = self.g;
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi);
+        ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+    }
+
+    /// Density of scattering by an angle with the given cosine, per unit solid angle.
+    fn phase(&self, cos_theta: Float) -> Float {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+}
+
+// The scattering angle is measured between the direction the light travelled in and the scattered direction.
+impl Reflection for HenyeyGreenstein {
+    fn eval(&self, _si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3 {
+        self.color * self.phase(-wo.dot(&wi))
+    }
+
+    fn sample(&self, _si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let cos_theta = self.sample_cos_theta();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rand();
+        let wi = Onb::from_w(-wo).to_world(Vec3::new(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        ));
+        Some(BsdfSample {
+            wi,
+            weight: self.color,
+            pdf: self.phase(cos_theta),
+            specular: false,
+        })
+    }
+
+    fn pdf(&self, _si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float {
+        self.phase(-wo.dot(&wi))
+    }
+}
+
+/// A scattering event at distance t inside a volume.
+/// A volume has no surface, so the normal just faces the incoming ray.
+/// Returns the fraction of the light that is scattered rather than absorbed, or 0 in an empty medium.
+/// Panics if a coefficient is negative.
+fn albedo(sigma_a: Float, sigma_s: Float) -> Float {
+    assert!(
+        sigma_a >= 0.0 && sigma_s >= 0.0,
+        "absorption and scattering coefficients must not be negative"
+    );
+    let sigma_t = sigma_a + sigma_s;
+    if sigma_t > 0.0 {
+        sigma_s / sigma_t
+    } else {
+        0.0
+    }
+}
+
+fn scattering<'a>(t: Float, h: &HitAttr, phase: &'a HenyeyGreenstein) -> SurfaceInteraction<'a> {
+    let normal = -h.ray.direction;
+    SurfaceInteraction::new(t, h, normal, [0.0, 0.0], tangent_frame(normal), phase)
+}
+
+/// A volume of constant density(e.g. fog, smoke, milk) filling a closed boundary object.
+/// `sigma_a` and `sigma_s` are the absorption and scattering coefficients per unit distance.
+/// Rays travel through it until they scatter at a random distance, so the RayBouncer treats
+/// the scattering events like surface hits. Light scatters according to the Henyey-Greenstein phase function.
+/// The boundary itself is invisible, and has to provide intervals(see Hittable::get_intervals).
+pub struct ConstantMedium<H: Hittable> {
+    boundary: H,
+    sigma_t: Float,
+    phase: HenyeyGreenstein,
+}
+
+impl<H> ConstantMedium<H>
+where
+    H: Hittable,
+{
+    /// `g` is the asymmetry of the phase function, and `color` tints the scattered light.
+    /// Panics if a coefficient is negative.
+    pub fn new(boundary: H, sigma_a: Float, sigma_s: Float, g: Float, color: Vec3) -> Self {
+        let sigma_t = sigma_a + sigma_s;
+        ConstantMedium {
+            boundary,
+            sigma_t,
+            // Each scattering event only keeps the scattered fraction of the light, the rest is absorbed.
+            phase: HenyeyGreenstein::new(g, color * albedo(sigma_a, sigma_s)),
+        }
+    }
+}
+
+impl<H> Hittable for ConstantMedium<H>
+where
+    H: Hittable,
+{
+    // The distance to the next scattering event is exponentially distributed.
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        for (t_in, t_out) in self.boundary.get_intervals(h) {
+            let t_out = t_out.min(h.ray.t_max);
+            if t_out <= h.ray.t_min {
+                continue;
+            }
+            let t = t_in.max(h.ray.t_min) - (1.0 - rand()).ln() / self.sigma_t;
+            if t < t_out {
+                return Some(scattering(t, h, &self.phase));
+            }
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.boundary.bounding_box()
+    }
+}
+
+#[derive(Debug)]
+pub enum VoxelError {
+    Io(std::io::Error),
+    Format(String),
+}
+
+impl fmt::Display for VoxelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoxelError::Io(e) => write!(f, "failed to read voxel file: {e}"),
+            VoxelError::Format(message) => write!(f, "invalid voxel file: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for VoxelError {}
+
+impl From<std::io::Error> for VoxelError {
+    fn from(e: std::io::Error) -> Self {
+        VoxelError::Io(e)
+    }
+}
+
+/// A grid of density samples, one at the center of each voxel.
+/// Densities are stored with x varying fastest, then y, then z.
+pub struct VoxelGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    densities: Vec<Float>,
+}
+
+impl VoxelGrid {
+    /// Panics if the number of densities does not match the size of the grid.
+    pub fn new(nx: usize, ny: usize, nz: usize, densities: Vec<Float>) -> Self {
+        assert_eq!(
+            densities.len(),
+            nx * ny * nz,
+            "expected {nx}x{ny}x{nz} density samples"
+        );
+        VoxelGrid {
+            nx,
+            ny,
+            nz,
+            densities,
+        }
+    }
+
+    /// Loads a raw voxel file: the grid size as three little-endian u32 (nx, ny, nz),
+    /// followed by nx * ny * nz little-endian f32 densities in the order of VoxelGrid.
+    /// Densities must be finite and non-negative.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, VoxelError> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, VoxelError> {
+        let chunks = bytes.chunks_exact(4);
+        let truncated = !chunks.remainder().is_empty();
+        let words: Vec<[u8; 4]> = chunks
+            .map(|chunk| [chunk[0], chunk[1], chunk[2], chunk[3]])
+            .collect();
+        if truncated || words.len() < 3 {
+            return Err(VoxelError::Format("truncated file".to_string()));
+        }
+        let [nx, ny, nz] = [0, 1, 2].map(|i| u32::from_le_bytes(words[i]) as usize);
+        let count = nx
+            .checked_mul(ny)
+            .and_then(|n| n.checked_mul(nz))
+            .ok_or_else(|| VoxelError::Format(format!("grid {nx}x{ny}x{nz} is too large")))?;
+        if words.len() - 3 != count {
+            return Err(VoxelError::Format(format!(
+                "expected {count} densities for a {nx}x{ny}x{nz} grid, found {}",
+                words.len() - 3
+            )));
+        }
+        let densities: Vec<Float> = words[3..]
+            .iter()
+            .map(|&word| f32::from_le_bytes(word) as Float)
+            .collect();
+        if let Some(d) = densities.iter().find(|d| !(d.is_finite() && **d >= 0.0)) {
+            return Err(VoxelError::Format(format!("invalid density {d}")));
+        }
+        Ok(Self::new(nx, ny, nz, densities))
+    }
+
+    fn at(&self, i: usize, j: usize, k: usize) -> Float {
+        self.densities[(k * self.ny + j) * self.nx + i]
+    }
+
+    /// Trilinearly interpolated density at the given position in grid space, where the grid spans [0, 1] on each axis.
+    /// Returns 0 outside the grid.
+    pub fn density(&self, p: Vec3) -> Float {
+        if self.densities.is_empty()
+            || !(0.0..=1.0).contains(&p.x)
+            || !(0.0..=1.0).contains(&p.y)
+            || !(0.0..=1.0).contains(&p.z)
+        {
+            return 0.0;
+        }
+        // Index of the lower sample and the weight of the upper one along an axis.
+        let axis = |x: Float, n: usize| {
+            let x = (x * n as Float - 0.5).clamp(0.0, (n - 1) as Float);
+            let i = (x as usize).min(n.saturating_sub(2));
+            (i, (i + 1).min(n - 1), x - i as Float)
+        };
+        let (i_0, i_1, u) = axis(p.x, self.nx);
+        let (j_0, j_1, v) = axis(p.y, self.ny);
+        let (k_0, k_1, w) = axis(p.z, self.nz);
+        let lerp = |a: Float, b: Float, t: Float| a + (b - a) * t;
+        lerp(
+            lerp(
+                lerp(self.at(i_0, j_0, k_0), self.at(i_1, j_0, k_0), u),
+                lerp(self.at(i_0, j_1, k_0), self.at(i_1, j_1, k_0), u),
+                v,
+            ),
+            lerp(
+                lerp(self.at(i_0, j_0, k_1), self.at(i_1, j_0, k_1), u),
+                lerp(self.at(i_0, j_1, k_1), self.at(i_1, j_1, k_1), u),
+                v,
+            ),
+            w,
+        )
+    }
+
+    pub fn max_density(&self) -> Float {
+        self.densities.iter().fold(0.0, |max, &d| max.max(d))
+    }
+}
+
+/// A volume whose density varies over a voxel grid(e.g. clouds, smoke simulations), stretched over `bounds`.
+/// `sigma_a` and `sigma_s` are the absorption and scattering coefficients at density 1.
+/// Scattering events are found by delta tracking against the maximum density of the grid,
+/// so rays pass quickly through thin parts of the volume. Occlusion queries use ratio tracking instead.
+pub struct HeterogeneousMedium {
+    grid: VoxelGrid,
+    bounds: Aabb,
+    sigma_t: Float,
+    // Majorant of the extinction coefficient over the whole grid.
+    sigma_max: Float,
+    phase: HenyeyGreenstein,
+}
+
+impl HeterogeneousMedium {
+    /// `g` is the asymmetry of the phase function, and `color` tints the scattered light.
+    /// Panics if a coefficient is negative.
+    pub fn new(
+        grid: VoxelGrid,
+        bounds: Aabb,
+        sigma_a: Float,
+        sigma_s: Float,
+        g: Float,
+        color: Vec3,
+    ) -> Self {
+        let sigma_t = sigma_a + sigma_s;
+        HeterogeneousMedium {
+            sigma_max: grid.max_density() * sigma_t,
+            grid,
+            bounds,
+            sigma_t,
+            phase: HenyeyGreenstein::new(g, color * albedo(sigma_a, sigma_s)),
+        }
+    }
+
+    /// Density at a point in world space.
+    fn density(&self, p: Vec3) -> Float {
+        let d = p - self.bounds.min;
+        let size = self.bounds.max - self.bounds.min;
+        self.grid
+            .density(Vec3::new(d.x / size.x, d.y / size.y, d.z / size.z))
+    }
+
+    /// Returns the fraction of light that passes through the volume within the interval of the ray.
+    /// The estimate is unbiased but noisy. Implementation is based on ratio tracking,
+    /// Novák et al., "Residual Ratio Tracking for Estimating Attenuation in Participating Media".
+    pub fn transmittance(&self, ray: &Ray) -> Float {
+        let (mut t, t_1) = match self.bounds.intersect(ray) {
+            Some(range) if self.sigma_max > 0.0 => range,
+            _ => return 1.0,
+        };
+        let mut transmittance = 1.0;
+        loop {
+            t -= (1.0 - rand()).ln() / self.sigma_max;
+            if t >= t_1 {
+                return transmittance;
+            }
+            transmittance *= 1.0 - self.density(ray.at(t)) * self.sigma_t / self.sigma_max;
+        }
+    }
+}
+
+impl Hittable for HeterogeneousMedium {
+    // Implementation is based on delta tracking: tentative collisions are sampled with the majorant,
+    // and each one is a real collision with the ratio of the local extinction to the majorant.
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        if self.sigma_max <= 0.0 {
+            return None;
+        }
+        let (mut t, t_1) = self.bounds.intersect(&h.ray)?;
+        loop {
+            t -= (1.0 - rand()).ln() / self.sigma_max;
+            if t >= t_1 {
+                return None;
+            }
+            if rand() * self.sigma_max < self.density(h.ray.at(t)) * self.sigma_t {
+                return Some(scattering(t, h, &self.phase));
+            }
+        }
+    }
+
+    // Visible with a probability equal to the ratio tracking estimate of the transmittance.
+    fn occluded(&self, h: &HitAttr) -> bool {
+        rand() >= self.transmittance(&h.ray)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel_file(size: [u32; 3], densities: &[f32]) -> Vec<u8> {
+        let header = size.iter().flat_map(|n| n.to_le_bytes());
+        header
+            .chain(densities.iter().flat_map(|d| d.to_le_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_voxel_grid() {
+        let grid = VoxelGrid::parse(&voxel_file([2, 1, 1], &[0.25, 0.75])).unwrap();
+        assert_eq!((grid.nx, grid.ny, grid.nz), (2, 1, 1));
+        assert_eq!(grid.max_density(), 0.75);
+        assert_eq!(grid.density(Vec3::new(0.5, 0.5, 0.5)), 0.5);
+        assert_eq!(grid.density(Vec3::new(1.5, 0.5, 0.5)), 0.0);
+    }
+
+    #[test]
+    fn invalid_densities() {
+        for density in [-0.5, f32::NAN, f32::INFINITY] {
+            let bytes = voxel_file([2, 1, 1], &[1.0, density]);
+            assert!(matches!(
+                VoxelGrid::parse(&bytes),
+                Err(VoxelError::Format(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn voxel_format_errors() {
+        let is_format_error =
+            |bytes: &[u8]| matches!(VoxelGrid::parse(bytes), Err(VoxelError::Format(_)));
+        // Header cut short.
+        assert!(is_format_error(&[1, 0, 0, 0, 1, 0, 0, 0]));
+        // Not a whole number of words.
+        let mut bytes = voxel_file([1, 1, 1], &[1.0]);
+        bytes.push(0);
+        assert!(is_format_error(&bytes));
+        // Fewer or more densities than the grid size.
+        assert!(is_format_error(&voxel_file([2, 2, 1], &[1.0; 3])));
+        assert!(is_format_error(&voxel_file([2, 2, 1], &[1.0; 5])));
+        // The number of densities overflows.
+        assert!(is_format_error(&voxel_file([u32::MAX; 3], &[])));
+    }
+}
@@ -0,0 +1,472 @@
+use crate::bvh::{Aabb, Bvh};
+use crate::math::{Float, Ray, Vec3};
+use crate::object::{pair_crossings, tangent_frame, Hittable, SurfaceInteraction};
+use crate::reflection::{HitAttr, Reflection};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Indexed triangle data. `normals` and `uvs` are either empty or have one entry per position.
+/// Triangles are wound counter-clockwise when seen from the outside.
+#[derive(Clone, Default)]
+pub struct MeshData {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<[Float; 2]>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl MeshData {
+    /// Returns the corners of the i-th triangle.
+    pub fn triangle(&self, i: usize) -> [Vec3; 3] {
+        let [a, b, c] = self.indices[i];
+        [
+            self.positions[a as usize],
+            self.positions[b as usize],
+            self.positions[c as usize],
+        ]
+    }
+
+    /// Returns the distance and the barycentric coordinates of the hit on the i-th triangle.
+    /// The distance is negative if the triangle is behind the origin of the ray.
+    pub fn intersect_triangle(&self, i: usize, h: &HitAttr) -> Option<(Float, Float, Float)> {
+        intersect_triangle(self.triangle(i), &h.ray)
+    }
+
+    /// Returns the geometric normal of the i-th triangle, on the side of the normal at the given barycentric coordinates
+    /// in case the winding disagrees with the vertex normals.
+    pub fn face_normal(&self, i: usize, b_1: Float, b_2: Float) -> Vec3 {
+        let [p_0, p_1, p_2] = self.triangle(i);
+        let face_normal = (p_1 - p_0).cross(&(p_2 - p_0)).normalize();
+        if face_normal.dot(&self.normal(i, b_1, b_2)) < 0.0 {
+            -face_normal
+        } else {
+            face_normal
+        }
+    }
+
+    /// Returns the normal of the i-th triangle at the given barycentric coordinates.
+    /// Vertex normals are interpolated if the mesh has them, otherwise the face normal is used.
+    pub fn normal(&self, i: usize, b_1: Float, b_2: Float) -> Vec3 {
+        if self.normals.is_empty() {
+            let [p_0, p_1, p_2] = self.triangle(i);
+            return (p_1 - p_0).cross(&(p_2 - p_0)).normalize();
+        }
+        let [a, b, c] = self.indices[i];
+        (self.normals[a as usize] * (1.0 - b_1 - b_2)
+            + self.normals[b as usize] * b_1
+            + self.normals[c as usize] * b_2)
+            .normalize()
+    }
+
+    /// Returns the texture coordinates of the corners of the i-th triangle.
+    /// Without texture coordinates, every triangle covers the lower left half of the unit square.
+    fn triangle_uvs(&self, i: usize) -> [[Float; 2]; 3] {
+        if self.uvs.is_empty() {
+            return [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]];
+        }
+        self.indices[i].map(|index| self.uvs[index as usize])
+    }
+
+    /// Returns the texture coordinates of the i-th triangle at the given barycentric coordinates.
+    pub fn uv(&self, i: usize, b_1: Float, b_2: Float) -> [Float; 2] {
+        let [uv_0, uv_1, uv_2] = self.triangle_uvs(i);
+        let b_0 = 1.0 - b_1 - b_2;
+        [
+            uv_0[0] * b_0 + uv_1[0] * b_1 + uv_2[0] * b_2,
+            uv_0[1] * b_0 + uv_1[1] * b_1 + uv_2[1] * b_2,
+        ]
+    }
+
+    /// Returns the partial derivatives of the position by u and v on the i-th triangle.
+    pub fn tangents(&self, i: usize) -> (Vec3, Vec3) {
+        triangle_tangents(self.triangle(i), self.triangle_uvs(i))
+    }
+}
+
+/// Returns the partial derivatives of the position by u and v on a triangle with the given texture coordinates.
+/// Falls back to an arbitrary tangent frame if the texture coordinates are degenerate.
+pub fn triangle_tangents(
+    [p_0, p_1, p_2]: [Vec3; 3],
+    [uv_0, uv_1, uv_2]: [[Float; 2]; 3],
+) -> (Vec3, Vec3) {
+    let duv_02 = [uv_0[0] - uv_2[0], uv_0[1] - uv_2[1]];
+    let duv_12 = [uv_1[0] - uv_2[0], uv_1[1] - uv_2[1]];
+    let dp_02 = p_0 - p_2;
+    let dp_12 = p_1 - p_2;
+    let det = duv_02[0] * duv_12[1] - duv_02[1] * duv_12[0];
+    if det.abs() < 1e-12 {
+        return tangent_frame((p_1 - p_0).cross(&(p_2 - p_0)));
+    }
+    let inv_det = 1.0 / det;
+    (
+        (dp_02 * duv_12[1] - dp_12 * duv_02[1]) * inv_det,
+        (dp_12 * duv_02[0] - dp_02 * duv_12[0]) * inv_det,
+    )
+}
+
+/// Returns the distance and the barycentric coordinates of the hit on the triangle.
+/// The distance is negative if the triangle is behind the origin of the ray.
+/// Implementation is based on the Möller–Trumbore algorithm.
+pub fn intersect_triangle([p_0, p_1, p_2]: [Vec3; 3], ray: &Ray) -> Option<(Float, Float, Float)> {
+    let edge_1 = p_1 - p_0;
+    let edge_2 = p_2 - p_0;
+    let p_vec = ray.direction.cross(&edge_2);
+    let det = edge_1.dot(&p_vec);
+    // The ray is parallel to the triangle.
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let t_vec = ray.origin - p_0;
+    let b_1 = t_vec.dot(&p_vec) * inv_det;
+    if !(0.0..=1.0).contains(&b_1) {
+        return None;
+    }
+    let q_vec = t_vec.cross(&edge_1);
+    let b_2 = ray.direction.dot(&q_vec) * inv_det;
+    if b_2 < 0.0 || b_1 + b_2 > 1.0 {
+        return None;
+    }
+    Some((edge_2.dot(&q_vec) * inv_det, b_1, b_2))
+}
+
+/// A triangle mesh with a single reflection. The mesh data is shared, so the same mesh can be used by several objects.
+/// Triangles are looked up through a BVH built on construction.
+pub struct TriangleMesh<R: Reflection> {
+    mesh: Arc<MeshData>,
+    bvh: Bvh,
+    reflection: R,
+}
+
+impl<R> TriangleMesh<R>
+where
+    R: Reflection,
+{
+    pub fn new(mesh: Arc<MeshData>, reflection: R) -> Self {
+        let boxes: Vec<Aabb> = (0..mesh.indices.len())
+            .map(|i| Aabb::from_points(&mesh.triangle(i)))
+            .collect();
+        TriangleMesh {
+            bvh: Bvh::new(&boxes),
+            mesh,
+            reflection,
+        }
+    }
+
+    /// Returns the distance, the triangle index and the barycentric coordinates of the closest hit.
+    fn closest_hit(&self, h: &HitAttr) -> Option<(Float, usize, Float, Float)> {
+        let mut barycentric = (0.0, 0.0);
+        let (t, i) = self.bvh.closest_hit(&h.ray, |i, t_max| {
+            let (t, b_1, b_2) = self.mesh.intersect_triangle(i, h)?;
+            if h.ray.t_min < t && t < t_max {
+                barycentric = (b_1, b_2);
+                Some(t)
+            } else {
+                None
+            }
+        })?;
+        Some((t, i, barycentric.0, barycentric.1))
+    }
+}
+
+impl<R> Hittable for TriangleMesh<R>
+where
+    R: Reflection,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let (t, i, b_1, b_2) = self.closest_hit(h)?;
+        let shading_normal = self.mesh.normal(i, b_1, b_2);
+        let normal = self.mesh.face_normal(i, b_1, b_2);
+        Some(
+            SurfaceInteraction::new(
+                t,
+                h,
+                normal,
+                self.mesh.uv(i, b_1, b_2),
+                self.mesh.tangents(i),
+                &self.reflection,
+            )
+            .with_shading_normal(shading_normal),
+        )
+    }
+
+    fn occluded(&self, h: &HitAttr) -> bool {
+        self.bvh.any_hit(&h.ray, |i| {
+            self.mesh
+                .intersect_triangle(i, h)
+                .is_some_and(|(t, _, _)| h.ray.contains(t))
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(&self.mesh.positions))
+    }
+
+    // Only meaningful for closed meshes. The whole line through the ray is traversed.
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        let line = h.ray.with_interval(Float::NEG_INFINITY, Float::INFINITY);
+        let mut crossings = Vec::new();
+        self.bvh.for_each_candidate(&line, |i| {
+            if let Some((t, b_1, b_2)) = self.mesh.intersect_triangle(i, h) {
+                let entering = h.ray.direction.dot(&self.mesh.face_normal(i, b_1, b_2)) < 0.0;
+                crossings.push((t, entering));
+            }
+        });
+        pair_crossings(crossings)
+    }
+}
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(std::io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io(e) => write!(f, "failed to read obj file: {e}"),
+            ObjError::Parse { line, message } => write!(f, "obj line {line}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+impl From<std::io::Error> for ObjError {
+    fn from(e: std::io::Error) -> Self {
+        ObjError::Io(e)
+    }
+}
+
+/// A group(`g` or `o` statement) of a Wavefront OBJ file.
+pub struct ObjGroup {
+    pub name: String,
+    pub mesh: Arc<MeshData>,
+}
+
+/// The groups of a Wavefront OBJ file. Faces before the first group statement belong to the group "default".
+/// Polygons are triangulated as fans, and materials(`usemtl`, `mtllib`) are ignored.
+pub struct ObjModel {
+    pub groups: Vec<ObjGroup>,
+}
+
+impl ObjModel {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ObjError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, ObjError> {
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut groups = Vec::new();
+        let mut group = GroupBuilder::new("default");
+
+        for (line_index, line) in source.lines().enumerate() {
+            let line_number = line_index + 1;
+            let error = |message: &str| ObjError::Parse {
+                line: line_number,
+                message: message.to_string(),
+            };
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => {
+                    positions.push(parse_vec3(tokens).ok_or_else(|| error("invalid vertex"))?)
+                }
+                Some("vn") => {
+                    normals.push(parse_vec3(tokens).ok_or_else(|| error("invalid normal"))?)
+                }
+                Some("vt") => {
+                    let u = tokens.next().and_then(|s| s.parse().ok());
+                    let v = tokens.next().and_then(|s| s.parse().ok()).or(Some(0.0));
+                    match (u, v) {
+                        (Some(u), Some(v)) => uvs.push([u, v]),
+                        _ => return Err(error("invalid texture coordinate")),
+                    }
+                }
+                Some("f") => {
+                    let mut face = Vec::new();
+                    for token in tokens {
+                        let vertex =
+                            parse_face_vertex(token, positions.len(), uvs.len(), normals.len())
+                                .ok_or_else(|| error(&format!("invalid face vertex '{token}'")))?;
+                        face.push(vertex);
+                    }
+                    if face.len() < 3 {
+                        return Err(error("face with less than 3 vertices"));
+                    }
+                    let face: Vec<u32> = face
+                        .into_iter()
+                        .map(|v| group.vertex(v, &positions, &uvs, &normals))
+                        .collect();
+                    for i in 1..face.len() - 1 {
+                        group.mesh.indices.push([face[0], face[i], face[i + 1]]);
+                    }
+                }
+                Some("g") | Some("o") => {
+                    let name = tokens.collect::<Vec<_>>().join(" ");
+                    let finished = std::mem::replace(&mut group, GroupBuilder::new(&name));
+                    groups.extend(finished.build());
+                }
+                _ => {}
+            }
+        }
+        groups.extend(group.build());
+        Ok(ObjModel { groups })
+    }
+
+    /// Returns the group with the given name.
+    pub fn group(&self, name: &str) -> Option<&ObjGroup> {
+        self.groups.iter().find(|g| g.name == name)
+    }
+}
+
+/// Collects the triangles of a group, and merges face vertices with the same (position, uv, normal) indices.
+struct GroupBuilder {
+    name: String,
+    mesh: MeshData,
+    vertices: HashMap<FaceVertex, u32>,
+    has_normals: bool,
+    has_uvs: bool,
+}
+
+/// Zero-based (position, uv, normal) indices of a face vertex.
+type FaceVertex = (usize, Option<usize>, Option<usize>);
+
+impl GroupBuilder {
+    fn new(name: &str) -> Self {
+        GroupBuilder {
+            name: name.to_string(),
+            mesh: MeshData::default(),
+            vertices: HashMap::new(),
+            has_normals: true,
+            has_uvs: true,
+        }
+    }
+
+    fn vertex(
+        &mut self,
+        v: FaceVertex,
+        positions: &[Vec3],
+        uvs: &[[Float; 2]],
+        normals: &[Vec3],
+    ) -> u32 {
+        if let Some(&index) = self.vertices.get(&v) {
+            return index;
+        }
+        let (p, uv, n) = v;
+        let index = self.mesh.positions.len() as u32;
+        self.mesh.positions.push(positions[p]);
+        self.mesh.uvs.push(uv.map_or([0.0, 0.0], |i| uvs[i]));
+        self.mesh
+            .normals
+            .push(n.map_or(Vec3::zero(), |i| normals[i]));
+        self.has_uvs &= uv.is_some();
+        self.has_normals &= n.is_some();
+        self.vertices.insert(v, index);
+        index
+    }
+
+    /// Returns None if the group has no faces. Normals and uvs are dropped unless every vertex has one.
+    fn build(mut self) -> Option<ObjGroup> {
+        if self.mesh.indices.is_empty() {
+            return None;
+        }
+        if !self.has_normals {
+            self.mesh.normals.clear();
+        }
+        if !self.has_uvs {
+            self.mesh.uvs.clear();
+        }
+        Some(ObjGroup {
+            name: self.name,
+            mesh: Arc::new(self.mesh),
+        })
+    }
+}
+
+fn parse_vec3<'a>(mut tokens: impl Iterator<Item = &'a str>) -> Option<Vec3> {
+    let mut next = || tokens.next().and_then(|s| s.parse().ok());
+    Some(Vec3::new(next()?, next()?, next()?))
+}
+
+/// Parses a face vertex of the form `v`, `v/vt`, `v//vn` or `v/vt/vn`.
+/// Indices are one-based, and negative indices count backwards from the latest element.
+fn parse_face_vertex(
+    token: &str,
+    positions: usize,
+    uvs: usize,
+    normals: usize,
+) -> Option<FaceVertex> {
+    let resolve = |s: &str, len: usize| -> Option<usize> {
+        let index: i64 = s.parse().ok()?;
+        let index = if index < 0 {
+            len as i64 + index
+        } else {
+            index - 1
+        };
+        if 0 <= index && index < len as i64 {
+            Some(index as usize)
+        } else {
+            None
+        }
+    };
+    let mut parts = token.split('/');
+    let p = resolve(parts.next()?, positions)?;
+    let uv = match parts.next() {
+        Some("") | None => None,
+        Some(s) => Some(resolve(s, uvs)?),
+    };
+    let n = match parts.next() {
+        Some("") | None => None,
+        Some(s) => Some(resolve(s, normals)?),
+    };
+    Some((p, uv, n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn obj_groups_split_faces() {
+        let model = ObjModel::parse(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\n\
+             f 1 2 3\n\
+             g quad\nf 1 2 4 3\n\
+             o empty\n",
+        )
+        .unwrap();
+        let names: Vec<&str> = model.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, ["default", "quad"]);
+        assert_eq!(model.group("default").unwrap().mesh.indices.len(), 1);
+        let quad = &model.group("quad").unwrap().mesh;
+        assert_eq!(quad.indices, [[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(quad.positions[3], Vec3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn obj_negative_indices() {
+        let model = ObjModel::parse(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0.5 0.5\nvn 0 0 1\n\
+             f -3/-1/-1 -2/-1/-1 -1/-1/-1\n",
+        )
+        .unwrap();
+        let mesh = &model.groups[0].mesh;
+        assert_eq!(mesh.positions[0], Vec3::zero());
+        assert_eq!(mesh.positions[2], Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(mesh.uvs, [[0.5, 0.5]; 3]);
+        assert_eq!(mesh.normals, [Vec3::new(0.0, 0.0, 1.0); 3]);
+    }
+
+    #[test]
+    fn obj_index_out_of_range() {
+        match ObjModel::parse("v 0 0 0\nv 1 0 0\nf 1 2 -3\n") {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 3),
+            _ => panic!("expected a parse error"),
+        }
+    }
+}
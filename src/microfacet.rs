@@ -0,0 +1,179 @@
+use crate::math::{rand, Float, Vec3, PI};
+
+/// The GGX(Trowbridge-Reitz) distribution of microfacet normals, with Smith masking-shadowing.
+/// All directions are in the local frame of the surface, where the macroscopic normal is the z axis,
+/// and point away from the surface.
+#[derive(Debug, Clone, Copy)]
+pub struct Ggx {
+    alpha: Float,
+}
+
+impl Ggx {
+    /// Roughness in [0, 1] is remapped to alpha = roughness^2, which looks perceptually linear.
+    /// A tiny minimum alpha keeps perfectly smooth surfaces numerically stable.
+    pub fn from_roughness(roughness: Float) -> Self {
+        Ggx {
+            alpha: roughness.clamp(0.0, 1.0).powi(2).max(1e-3),
+        }
+    }
+
+    pub fn alpha(&self) -> Float {
+        self.alpha
+    }
+
+    /// Density of microfacet normals m, per unit projected area.
+    pub fn d(&self, m: Vec3) -> Float {
+        if m.z <= 0.0 {
+            return 0.0;
+        }
+        let alpha_2 = self.alpha * self.alpha;
+        let denominator = m.z * m.z * (alpha_2 - 1.0) + 1.0;
+        alpha_2 / (PI * denominator * denominator)
+    }
+
+    /// Smith's auxiliary function, the ratio of hidden to visible microfacet area seen from w.
+    fn lambda(&self, w: Vec3) -> Float {
+        let cos_2 = w.z * w.z;
+        if cos_2 <= 0.0 {
+            return Float::INFINITY;
+        }
+        let tan_2 = (1.0 - cos_2).max(0.0) / cos_2;
+        ((1.0 + self.alpha * self.alpha * tan_2).sqrt() - 1.0) * 0.5
+    }
+
+    /// Fraction of microfacets visible from w.
+    pub fn g1(&self, w: Vec3) -> Float {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Fraction of microfacets visible from both wo and wi(height-correlated).
+    pub fn g2(&self, wo: Vec3, wi: Vec3) -> Float {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Samples a microfacet normal visible from wo, proportional to its projected area.
+    /// Implementation is based on Heitz, "Sampling the GGX Distribution of Visible Normals".
+    pub fn sample_visible_normal(&self, wo: Vec3) -> Vec3 {
+        // Stretch the view direction, so the distribution becomes a hemisphere.
+        let v = Vec3::new(self.alpha * wo.x, self.alpha * wo.y, wo.z).normalize();
+        let len_2 = v.x * v.x + v.y * v.y;
+        let t_1 = if len_2 > 0.0 {
+            Vec3::new(-v.y, v.x, 0.0) * (1.0 / len_2.sqrt())
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t_2 = v.cross(&t_1);
+        // Sample the projected hemisphere as a disk, with the half hidden behind it squashed away.
+        let r = rand().sqrt();
+        let phi = 2.0 * PI * rand();
+        let p_1 = r * phi.cos();
+        let s = 0.5 * (1.0 + v.z);
+        let p_2 = (1.0 - s) * (1.0 - p_1 * p_1).sqrt() + s * r * phi.sin();
+        let n = t_1 * p_1 + t_2 * p_2 + v * (1.0 - p_1 * p_1 - p_2 * p_2).max(0.0).sqrt();
+        // Unstretch back to the ellipsoid.
+        Vec3::new(self.alpha * n.x, self.alpha * n.y, n.z.max(0.0)).normalize()
+    }
+
+    /// Density of sample_visible_normal choosing the microfacet normal m.
+    pub fn pdf_visible_normal(&self, wo: Vec3, m: Vec3) -> Float {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(&m).max(0.0) * self.d(m) / wo.z
+    }
+
+    /// Reflects wo off a sampled visible microfacet, and returns the reflected direction and the microfacet normal.
+    /// Returns None if the reflection points below the surface.
+    pub fn sample_reflection(&self, wo: Vec3) -> Option<(Vec3, Vec3)> {
+        let m = self.sample_visible_normal(wo);
+        let wi = reflect(wo, m);
+        if wi.z > 0.0 {
+            Some((wi, m))
+        } else {
+            None
+        }
+    }
+
+    /// Density of sample_reflection choosing wi, per unit solid angle.
+    pub fn pdf_reflection(&self, wo: Vec3, wi: Vec3) -> Float {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let m = (wo + wi).normalize();
+        self.pdf_visible_normal(wo, m) / (4.0 * wo.dot(&m))
+    }
+
+    /// The microfacet BRDF times the cosine of wi, for a Fresnel reflectance of 1.
+    pub fn eval_reflection(&self, wo: Vec3, wi: Vec3) -> Float {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let m = (wo + wi).normalize();
+        self.d(m) * self.g2(wo, wi) / (4.0 * wo.z)
+    }
+}
+
+/// Reflects w at the plane with the given normal.
+pub fn reflect(w: Vec3, normal: Vec3) -> Vec3 {
+    normal * (2.0 * w.dot(&normal)) - w
+}
+
+/// Refracts w through the plane with the given normal, on the side of w.
+/// eta is the ratio of the index of refraction on the far side to the one on the side of w.
+/// Returns None for total internal reflection.
+pub fn refract(w: Vec3, normal: Vec3, eta: Float) -> Option<Vec3> {
+    let cos_i = w.dot(&normal);
+    let sin_2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin_2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin_2_t).sqrt();
+    Some(-w * (1.0 / eta) + normal * (cos_i / eta - cos_t))
+}
+
+/// Fresnel reflectance of a conductor with the complex index of refraction eta + ik, for one wavelength.
+/// `cos_i` is the cosine between the incoming direction and the normal.
+pub fn fresnel_conductor(cos_i: Float, eta: Float, k: Float) -> Float {
+    let cos_2 = cos_i.clamp(0.0, 1.0).powi(2);
+    let sin_2 = 1.0 - cos_2;
+    let eta_2 = eta * eta;
+    let k_2 = k * k;
+    let t_0 = eta_2 - k_2 - sin_2;
+    let a_2_plus_b_2 = (t_0 * t_0 + 4.0 * eta_2 * k_2).sqrt();
+    let t_1 = a_2_plus_b_2 + cos_2;
+    let a = (0.5 * (a_2_plus_b_2 + t_0)).max(0.0).sqrt();
+    let t_2 = 2.0 * cos_i.clamp(0.0, 1.0) * a;
+    let r_s = (t_1 - t_2) / (t_1 + t_2);
+    let t_3 = cos_2 * a_2_plus_b_2 + sin_2 * sin_2;
+    let t_4 = t_2 * sin_2;
+    let r_p = r_s * (t_3 - t_4) / (t_3 + t_4);
+    0.5 * (r_p + r_s)
+}
+
+/// Fresnel reflectance of a conductor for each color channel.
+pub fn fresnel_conductor_rgb(cos_i: Float, eta: Vec3, k: Vec3) -> Vec3 {
+    Vec3::new(
+        fresnel_conductor(cos_i, eta.x, k.x),
+        fresnel_conductor(cos_i, eta.y, k.y),
+        fresnel_conductor(cos_i, eta.z, k.z),
+    )
+}
+
+/// Schlick's approximation of the Fresnel reflectance, given the reflectance at normal incidence.
+pub fn fresnel_schlick(f_0: Vec3, cos_i: Float) -> Vec3 {
+    f_0 + (Vec3::one() - f_0) * (1.0 - cos_i.clamp(0.0, 1.0)).powi(5)
+}
+
+/// Fresnel reflectance of an interface between dielectrics, where eta is the ratio of the index of refraction
+/// on the far side to the one on the side of the incoming light. Returns 1 for total internal reflection.
+pub fn fresnel_dielectric(cos_i: Float, eta: Float) -> Float {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin_2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin_2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
@@ -1,10 +1,156 @@
-use crate::math::{Float, Vec3};
-use crate::reflection::{Hit, HitAttr, HitKind, Reflection};
+use crate::bvh::Aabb;
+use crate::math::{polynomial_roots, Float, Onb, Transform, Vec3, EPSILON, PI};
+use crate::reflection::{HitAttr, Reflection};
+use std::sync::Arc;
 
 pub trait Hittable {
-    fn reflect(&self, t: Float, p: Vec3, normal: Vec3, h: &HitAttr) -> HitAttr;
-    fn get_normal(&self, h: &HitAttr, p: Vec3) -> Vec3;
-    fn get_intersect(&self, h: &HitAttr) -> Option<Float>;
+    /// Returns the closest hit within the interval of the ray(see Ray::contains), with everything needed to shade it.
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>>;
+    /// Returns whether the ray hits the object anywhere within its interval, e.g. for shadow rays.
+    /// Objects should override this if they can stop at the first hit found instead of the closest one.
+    fn occluded(&self, h: &HitAttr) -> bool {
+        self.intersect(h).is_some()
+    }
+    /// Returns the box enclosing the object, or None if the object is unbounded(e.g. Floor).
+    fn bounding_box(&self) -> Option<Aabb>;
+    /// Returns the sorted, disjoint (entry, exit) distances along the ray where it is inside the object.
+    /// The whole line is considered, so distances behind the origin are negative. Used for constructive solid geometry.
+    /// Objects that do not enclose a volume(e.g. Quad) have no inside, and return no intervals.
+    fn get_intervals(&self, _h: &HitAttr) -> Vec<(Float, Float)> {
+        Vec::new()
+    }
+}
+
+/// The point where a ray hits a surface, and the local geometry around it.
+#[derive(Clone, Copy)]
+pub struct SurfaceInteraction<'a> {
+    /// Distance from the ray origin to the hit point.
+    pub t: Float,
+    pub p: Vec3,
+    /// Normal of the actual geometry. It points outwards for closed objects, and towards the ray for two-sided surfaces.
+    pub normal: Vec3,
+    /// Normal used for shading(e.g. interpolated over a mesh), on the same side as `normal`.
+    pub shading_normal: Vec3,
+    /// Whether the ray hits the front side: the side the normal points to for closed objects,
+    /// and the side the surface is oriented to for two-sided surfaces(e.g. the normal of a Quad).
+    pub front_face: bool,
+    /// Surface coordinates of the hit point, within [0, 1] for bounded surfaces.
+    pub uv: [Float; 2],
+    /// Partial derivatives of the hit point by u and v. They span the tangent plane.
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    /// The reflection of the surface that was hit.
+    pub reflection: &'a dyn Reflection,
+}
+
+impl<'a> SurfaceInteraction<'a> {
+    /// Constructs the interaction at distance t along the ray, with the shading normal equal to the geometric normal.
+    pub fn new(
+        t: Float,
+        h: &HitAttr,
+        normal: Vec3,
+        uv: [Float; 2],
+        (dpdu, dpdv): (Vec3, Vec3),
+        reflection: &'a dyn Reflection,
+    ) -> Self {
+        SurfaceInteraction {
+            t,
+            p: h.ray.at(t),
+            normal,
+            shading_normal: normal,
+            front_face: h.ray.direction.dot(&normal) < 0.0,
+            uv,
+            dpdu,
+            dpdv,
+            reflection,
+        }
+    }
+
+    pub fn with_shading_normal(mut self, shading_normal: Vec3) -> Self {
+        self.shading_normal = shading_normal;
+        self
+    }
+
+    /// Sets the side that was hit for two-sided surfaces, whose normal faces the ray on both sides.
+    /// `front` is the normal of the front side.
+    pub fn with_front_side(mut self, front: Vec3, h: &HitAttr) -> Self {
+        self.front_face = h.ray.direction.dot(&front) < 0.0;
+        self
+    }
+
+    /// Turns the surface inside out, e.g. for the cut-out operand of a Csg difference.
+    pub fn flip(mut self) -> Self {
+        self.normal = -self.normal;
+        self.shading_normal = -self.shading_normal;
+        self.front_face = !self.front_face;
+        self
+    }
+
+    /// Moves the interaction out of the local frame of an object. `t` is the distance along the world ray.
+    pub fn transform(mut self, transform: &Transform, t: Float) -> Self {
+        self.t = t;
+        self.p = transform.point(self.p);
+        self.normal = transform.normal(self.normal).normalize();
+        self.shading_normal = transform.normal(self.shading_normal).normalize();
+        self.dpdu = transform.vector(self.dpdu);
+        self.dpdv = transform.vector(self.dpdv);
+        self
+    }
+}
+
+/// Returns an arbitrary tangent frame around the normal, for surfaces without a natural parametrization(e.g. SdfObject).
+pub fn tangent_frame(normal: Vec3) -> (Vec3, Vec3) {
+    let onb = Onb::from_w(normal);
+    (onb.u, onb.v)
+}
+
+/// Pairs up the points where the ray crosses the surface of a closed object into (entry, exit) intervals.
+/// Each crossing is a (distance, entering) pair. Crossings in the same direction within EPSILON of each other
+/// (e.g. on an edge or a vertex shared by several triangles) count once, and entries and exits are counted,
+/// so duplicated crossings do not swap the inside and the outside.
+pub fn pair_crossings(mut crossings: Vec<(Float, bool)>) -> Vec<(Float, Float)> {
+    // Entries come first at the same distance, so grazing the surface gives an empty interval.
+    crossings.sort_by(|a, b| a.0.total_cmp(&b.0).then(b.1.cmp(&a.1)));
+    crossings.dedup_by(|b, a| a.1 == b.1 && b.0 - a.0 <= EPSILON);
+    let mut intervals = Vec::new();
+    let mut depth = 0;
+    let mut t_in = 0.0;
+    for (t, entering) in crossings {
+        if entering {
+            if depth == 0 {
+                t_in = t;
+            }
+            depth += 1;
+        } else if depth > 0 {
+            depth -= 1;
+            if depth == 0 && t > t_in {
+                intervals.push((t_in, t));
+            }
+        }
+    }
+    intervals
+}
+
+/// Shared objects can be rendered several times, e.g. one mesh used by many instances.
+impl<H> Hittable for Arc<H>
+where
+    H: Hittable + ?Sized,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        (**self).intersect(h)
+    }
+
+    fn occluded(&self, h: &HitAttr) -> bool {
+        (**self).occluded(h)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        (**self).bounding_box()
+    }
+
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        (**self).get_intervals(h)
+    }
 }
 
 pub struct Sphere<R: Reflection> {
@@ -32,48 +178,61 @@ impl<R> Hittable for Sphere<R>
 where
     R: Reflection,
 {
-    fn get_intersect(&self, h: &HitAttr) -> Option<Float> {
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
         let oc = h.ray.origin - self.center;
         let a = h.ray.direction.dot(&h.ray.direction);
         let b = oc.dot(&h.ray.direction);
         let c = oc.dot(&oc) - self.radius * self.radius;
         let discriminant = b * b - a * c;
-        if discriminant > 0.0 {
-            // If discriminant is positive, the ray intersects the sphere.
-            let d_sqrt = discriminant.sqrt();
-            if -b - d_sqrt > 0.0 {
-                // The ray starts from outside the sphere.
-                let t = (-b - d_sqrt) / a;
-                return Some(t);
-            } else if -b + d_sqrt > 0.0 {
-                // The ray starts from inside the sphere.
-                let t = (-b + d_sqrt) / a;
-                return Some(t);
-            }
-        }
         // If discriminant is negative, the ray misses the sphere
-        None
+        if discriminant <= 0.0 {
+            return None;
+        }
+        let d_sqrt = discriminant.sqrt();
+        let (t_0, t_1) = ((-b - d_sqrt) / a, (-b + d_sqrt) / a);
+        let t = if h.ray.contains(t_0) {
+            // The ray starts from outside the sphere.
+            t_0
+        } else if h.ray.contains(t_1) {
+            // The ray starts from inside the sphere.
+            t_1
+        } else {
+            return None;
+        };
+        // u goes around the z axis, and v from the bottom to the top of the sphere.
+        let d = h.ray.at(t) - self.center;
+        let normal = d * (1.0 / self.radius);
+        let phi = d.y.atan2(d.x);
+        let theta = normal.z.clamp(-1.0, 1.0).acos();
+        let dpdu = Vec3::new(-d.y, d.x, 0.0) * (2.0 * PI);
+        let dpdv = Vec3::new(d.z * phi.cos(), d.z * phi.sin(), -self.radius * theta.sin()) * -PI;
+        Some(SurfaceInteraction::new(
+            t,
+            h,
+            normal,
+            [phi / (2.0 * PI) + 0.5, 1.0 - theta / PI],
+            (dpdu, dpdv),
+            &self.reflection,
+        ))
     }
 
-    fn reflect(&self, t: Float, p: Vec3, normal: Vec3, h: &HitAttr) -> HitAttr {
-        match self.reflection.get_reflection(p, normal, h) {
-            // If the hit is a normal hit, (e.g. Diffusion, Mirror, Glass, etc.), return the hit.
-            Hit::NormalHit(r) => HitAttr {
-                t: t,
-                ray: r,
-                hitkind: HitKind::NormalHit,
-            },
-            // If the hit is a last hit, (e.g. DiffusedLightSource), return the hit.
-            Hit::LastHit(r) => HitAttr {
-                t: t,
-                ray: r,
-                hitkind: HitKind::LastHit,
-            },
-        }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Some(Aabb::new(self.center - r, self.center + r))
     }
 
-    fn get_normal(&self, _h: &HitAttr, p: Vec3) -> Vec3 {
-        (p - self.center) * (1.0 / self.radius)
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        let oc = h.ray.origin - self.center;
+        let a = h.ray.direction.dot(&h.ray.direction);
+        let b = oc.dot(&h.ray.direction);
+        let c = oc.dot(&oc) - self.radius * self.radius;
+        let discriminant = b * b - a * c;
+        if discriminant > 0.0 {
+            let d_sqrt = discriminant.sqrt();
+            vec![((-b - d_sqrt) / a, (-b + d_sqrt) / a)]
+        } else {
+            Vec::new()
+        }
     }
 }
 
@@ -101,40 +260,947 @@ impl<R> Hittable for Floor<R>
 where
     R: Reflection,
 {
-    fn get_intersect(&self, h: &HitAttr) -> Option<Float> {
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
         let t = (self.height - h.ray.origin.z) / h.ray.direction.z;
-        if t > 0.0 {
+        let normal = if self.upwards {
+            Vec3::new(0.0, 0.0, 1.0)
+        } else {
+            Vec3::new(0.0, 0.0, -1.0)
+        };
+        // The ray only reflects towards the upward direction.
+        if h.ray.contains(t) && h.ray.direction.dot(&normal) < 0.0 {
             let p = h.ray.at(t);
-            // The ray only reflects towards the upward direction.
-            if h.ray.direction.dot(&self.get_normal(h, p)) < 0.0 {
-                return Some(t);
-            }
+            Some(SurfaceInteraction::new(
+                t,
+                h,
+                normal,
+                [p.x, p.y],
+                (Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)),
+                &self.reflection,
+            ))
+        } else {
+            None
         }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
         None
     }
 
-    fn get_normal(&self, _h: &HitAttr, _p: Vec3) -> Vec3 {
-        if self.upwards {
-            Vec3::new(0.0, 0.0, 1.0)
+    // The floor is the boundary of a half-space. The solid side is the one the normal points away from.
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        let below = (h.ray.origin.z < self.height) == self.upwards;
+        if h.ray.direction.z == 0.0 {
+            return if below {
+                vec![(Float::NEG_INFINITY, Float::INFINITY)]
+            } else {
+                Vec::new()
+            };
+        }
+        let t = (self.height - h.ray.origin.z) / h.ray.direction.z;
+        if (h.ray.direction.z > 0.0) == self.upwards {
+            vec![(Float::NEG_INFINITY, t)]
         } else {
-            Vec3::new(0.0, 0.0, -1.0)
+            vec![(t, Float::INFINITY)]
+        }
+    }
+}
+
+/// Moves an object along a path given by keyframes, to render it with motion blur.
+/// Each keyframe is a (time, translation) pair, and the translation is linearly interpolated in between.
+/// Before the first and after the last keyframe the object stays still.
+/// Only translation is animated: a rotated or scaled object keeps its Instance transform during the motion.
+pub struct Moving<H: Hittable> {
+    object: H,
+    keyframes: Vec<(Float, Vec3)>,
+}
+
+impl<H> Moving<H>
+where
+    H: Hittable,
+{
+    pub fn new(object: H, mut keyframes: Vec<(Float, Vec3)>) -> Self {
+        keyframes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Moving { object, keyframes }
+    }
+
+    /// Returns the translation of the object at the given time.
+    pub fn offset(&self, time: Float) -> Vec3 {
+        let (first, last) = match (self.keyframes.first(), self.keyframes.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Vec3::zero(),
+        };
+        if time <= first.0 {
+            return first.1;
         }
+        if time >= last.0 {
+            return last.1;
+        }
+        let i = self.keyframes.partition_point(|k| k.0 <= time);
+        let (t_0, offset_0) = self.keyframes[i - 1];
+        let (t_1, offset_1) = self.keyframes[i];
+        offset_0 + (offset_1 - offset_0) * ((time - t_0) / (t_1 - t_0))
+    }
+
+    /// Moves the ray into the frame of the object at the time of the ray.
+    fn local(&self, h: &HitAttr) -> (HitAttr, Vec3) {
+        let offset = self.offset(h.ray.time);
+        let mut local = *h;
+        local.ray.origin = local.ray.origin - offset;
+        (local, offset)
     }
+}
 
-    fn reflect(&self, t: Float, p: Vec3, normal: Vec3, h: &HitAttr) -> HitAttr {
-        match self.reflection.get_reflection(p, normal, h) {
-            // If the hit is a normal hit, (e.g. Diffusion, Mirror, etc.), return the hit.
-            Hit::NormalHit(r) => HitAttr {
+impl<H> Hittable for Moving<H>
+where
+    H: Hittable,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let (local, offset) = self.local(h);
+        let mut si = self.object.intersect(&local)?;
+        si.p += offset;
+        Some(si)
+    }
+
+    fn occluded(&self, h: &HitAttr) -> bool {
+        self.object.occluded(&self.local(h).0)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Moving linearly between keyframes never leaves the union of the boxes at the keyframes.
+        let aabb = self.object.bounding_box()?;
+        Some(
+            self.keyframes
+                .iter()
+                .fold(aabb.translate(self.offset(0.0)), |union, (_, offset)| {
+                    union.union(&aabb.translate(*offset))
+                }),
+        )
+    }
+
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        self.object.get_intervals(&self.local(h).0)
+    }
+}
+
+/// Flips the normal of a two-sided surface so it faces against the incoming ray.
+pub(crate) fn facing(normal: Vec3, h: &HitAttr) -> Vec3 {
+    if h.ray.direction.dot(&normal) > 0.0 {
+        -normal
+    } else {
+        normal
+    }
+}
+
+/// Returns the distance to the plane through `point` with the given normal, if the ray hits it within its interval.
+fn intersect_plane(point: Vec3, normal: Vec3, h: &HitAttr) -> Option<Float> {
+    let denominator = h.ray.direction.dot(&normal);
+    if denominator.abs() < 1e-8 {
+        // The ray is parallel to the plane.
+        return None;
+    }
+    let t = (point - h.ray.origin).dot(&normal) / denominator;
+    if h.ray.contains(t) {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+/// Infinite plane through a point with an arbitrary normal, and a given reflection.
+/// The plane is two-sided, so the normal always faces the incoming ray.
+pub struct Plane<R: Reflection> {
+    point: Vec3,
+    normal: Vec3,
+    reflection: R,
+}
+
+impl<R> Plane<R>
+where
+    R: Reflection,
+{
+    pub fn new(point: Vec3, normal: Vec3, reflection: R) -> Self {
+        Plane {
+            point,
+            normal: normal.normalize(),
+            reflection,
+        }
+    }
+}
+
+impl<R> Hittable for Plane<R>
+where
+    R: Reflection,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let t = intersect_plane(self.point, self.normal, h)?;
+        // The plane is parametrized by the distances along two axes perpendicular to the normal.
+        let onb = Onb::from_w(self.normal);
+        let q = onb.to_local(h.ray.at(t) - self.point);
+        Some(
+            SurfaceInteraction::new(
                 t,
-                ray: r,
-                hitkind: HitKind::NormalHit,
-            },
-            // If the hit is a last hit, (e.g. DiffusedLightSource), return the hit.
-            Hit::LastHit(r) => HitAttr {
+                h,
+                facing(self.normal, h),
+                [q.x, q.y],
+                (onb.u, onb.v),
+                &self.reflection,
+            )
+            .with_front_side(self.normal, h),
+        )
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+}
+
+/// Parallelogram spanned by the edges `u` and `v` from a corner, and a given reflection.
+/// The quad is two-sided, so the normal always faces the incoming ray.
+pub struct Quad<R: Reflection> {
+    corner: Vec3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    // u x v divided by its squared length. Used to find the planar coordinates of a hit.
+    w: Vec3,
+    reflection: R,
+}
+
+impl<R> Quad<R>
+where
+    R: Reflection,
+{
+    pub fn new(corner: Vec3, u: Vec3, v: Vec3, reflection: R) -> Self {
+        let n = u.cross(&v);
+        Quad {
+            corner,
+            u,
+            v,
+            normal: n.normalize(),
+            w: n * (1.0 / n.dot(&n)),
+            reflection,
+        }
+    }
+}
+
+impl<R> Hittable for Quad<R>
+where
+    R: Reflection,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let t = intersect_plane(self.corner, self.normal, h)?;
+        let q = h.ray.at(t) - self.corner;
+        let alpha = self.w.dot(&q.cross(&self.v));
+        let beta = self.w.dot(&self.u.cross(&q));
+        if (0.0..=1.0).contains(&alpha) && (0.0..=1.0).contains(&beta) {
+            Some(
+                SurfaceInteraction::new(
+                    t,
+                    h,
+                    facing(self.normal, h),
+                    [alpha, beta],
+                    (self.u, self.v),
+                    &self.reflection,
+                )
+                .with_front_side(self.normal, h),
+            )
+        } else {
+            None
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(&[
+            self.corner,
+            self.corner + self.u,
+            self.corner + self.v,
+            self.corner + self.u + self.v,
+        ]))
+    }
+}
+
+/// Flat disk with a given center, normal and radius, and a given reflection.
+/// The disk is two-sided, so the normal always faces the incoming ray.
+pub struct Disk<R: Reflection> {
+    center: Vec3,
+    normal: Vec3,
+    radius: Float,
+    reflection: R,
+}
+
+impl<R> Disk<R>
+where
+    R: Reflection,
+{
+    pub fn new(center: Vec3, normal: Vec3, radius: Float, reflection: R) -> Self {
+        Disk {
+            center,
+            normal: normal.normalize(),
+            radius,
+            reflection,
+        }
+    }
+}
+
+impl<R> Hittable for Disk<R>
+where
+    R: Reflection,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let t = intersect_plane(self.center, self.normal, h)?;
+        let onb = Onb::from_w(self.normal);
+        let q = onb.to_local(h.ray.at(t) - self.center);
+        if q.x * q.x + q.y * q.y > self.radius * self.radius {
+            return None;
+        }
+        // The square around the disk is mapped to [0, 1].
+        let diameter = 2.0 * self.radius;
+        Some(
+            SurfaceInteraction::new(
+                t,
+                h,
+                facing(self.normal, h),
+                [0.5 + q.x / diameter, 0.5 + q.y / diameter],
+                (onb.u * diameter, onb.v * diameter),
+                &self.reflection,
+            )
+            .with_front_side(self.normal, h),
+        )
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // The extent of the disk along each axis is radius * sin(angle between the axis and the normal).
+        let n = self.normal;
+        let extent = Vec3::new(
+            (1.0 - n.x * n.x).max(0.0).sqrt(),
+            (1.0 - n.y * n.y).max(0.0).sqrt(),
+            (1.0 - n.z * n.z).max(0.0).sqrt(),
+        ) * self.radius;
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+}
+
+/// Axis-aligned box between the corners `min` and `max`, and a given reflection.
+/// Unlike the flat surfaces, the box is closed, so Glass works with it.
+pub struct AaBox<R: Reflection> {
+    aabb: Aabb,
+    reflection: R,
+}
+
+impl<R> AaBox<R>
+where
+    R: Reflection,
+{
+    pub fn new(min: Vec3, max: Vec3, reflection: R) -> Self {
+        AaBox {
+            aabb: Aabb::new(min, max),
+            reflection,
+        }
+    }
+}
+
+impl<R> Hittable for AaBox<R>
+where
+    R: Reflection,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let (t_0, t_1) = self
+            .aabb
+            .intersect_range(&h.ray, Float::NEG_INFINITY, Float::INFINITY)?;
+        let t = if h.ray.contains(t_0) {
+            // The ray starts from outside the box.
+            t_0
+        } else if h.ray.contains(t_1) {
+            // The ray starts from inside the box.
+            t_1
+        } else {
+            return None;
+        };
+        // The face that was hit is the one the point is relatively closest to.
+        let size = self.aabb.max - self.aabb.min;
+        let q = (h.ray.at(t) - self.aabb.min).div(&size);
+        let d = q - Vec3::new(0.5, 0.5, 0.5);
+        // Each face is parametrized by the two other axes, in the order x, y, z.
+        let (normal, uv, dpdu, dpdv) = if d.x.abs() >= d.y.abs() && d.x.abs() >= d.z.abs() {
+            (
+                Vec3::new(d.x.signum(), 0.0, 0.0),
+                [q.y, q.z],
+                Vec3::new(0.0, size.y, 0.0),
+                Vec3::new(0.0, 0.0, size.z),
+            )
+        } else if d.y.abs() >= d.z.abs() {
+            (
+                Vec3::new(0.0, d.y.signum(), 0.0),
+                [q.x, q.z],
+                Vec3::new(size.x, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, size.z),
+            )
+        } else {
+            (
+                Vec3::new(0.0, 0.0, d.z.signum()),
+                [q.x, q.y],
+                Vec3::new(size.x, 0.0, 0.0),
+                Vec3::new(0.0, size.y, 0.0),
+            )
+        };
+        Some(SurfaceInteraction::new(
+            t,
+            h,
+            normal,
+            uv,
+            (dpdu, dpdv),
+            &self.reflection,
+        ))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.aabb)
+    }
+
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        self.aabb
+            .intersect_range(&h.ray, Float::NEG_INFINITY, Float::INFINITY)
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Returns the bounding box of the local box between `min` and `max`, placed at `origin` in the frame `onb`.
+fn local_bounding_box(onb: &Onb, origin: Vec3, min: Vec3, max: Vec3) -> Aabb {
+    let mut corners = Vec::with_capacity(8);
+    for x in [min.x, max.x] {
+        for y in [min.y, max.y] {
+            for z in [min.z, max.z] {
+                corners.push(origin + onb.to_world(Vec3::new(x, y, z)));
+            }
+        }
+    }
+    Aabb::from_points(&corners)
+}
+
+/// The parts of the surface of a Frustum.
+#[derive(Clone, Copy)]
+enum FrustumPart {
+    Side,
+    Base,
+    Top,
+}
+
+/// Shared geometry of cylinders and cones: a (truncated) cone around an axis,
+/// with the local z axis along the axis and the base at z = 0.
+struct Frustum {
+    base: Vec3,
+    onb: Onb,
+    height: Float,
+    base_radius: Float,
+    top_radius: Float,
+    capped: bool,
+}
+
+impl Frustum {
+    fn new(base: Vec3, axis: Vec3, base_radius: Float, top_radius: Float, capped: bool) -> Self {
+        assert!(
+            axis.mag() > 0.0,
+            "the axis of a cylinder or cone must not be zero"
+        );
+        Frustum {
+            base,
+            onb: Onb::from_w(axis),
+            height: axis.mag(),
+            base_radius,
+            top_radius,
+            capped,
+        }
+    }
+
+    fn radius_at(&self, z: Float) -> Float {
+        self.base_radius + (self.top_radius - self.base_radius) * z / self.height
+    }
+
+    /// Returns the outward normal at a point in local space on the given part.
+    fn local_normal(&self, p: Vec3, part: FrustumPart) -> Vec3 {
+        match part {
+            FrustumPart::Side => {
+                // Gradient of x^2 + y^2 - r(z)^2, using r(z) = radial on the surface.
+                let k = (self.top_radius - self.base_radius) / self.height;
+                let radial = (p.x * p.x + p.y * p.y).sqrt();
+                Vec3::new(p.x, p.y, -k * radial).normalize()
+            }
+            FrustumPart::Base => Vec3::new(0.0, 0.0, -1.0),
+            FrustumPart::Top => Vec3::new(0.0, 0.0, 1.0),
+        }
+    }
+
+    /// Returns the distances of every point where the line through the ray crosses the surface, and the part crossed.
+    fn crossings(&self, h: &HitAttr) -> Vec<(Float, FrustumPart)> {
+        let o = self.onb.to_local(h.ray.origin - self.base);
+        let d = self.onb.to_local(h.ray.direction);
+        let mut crossings = Vec::new();
+        let mut consider = |t: Float, part: FrustumPart| crossings.push((t, part));
+
+        // The side satisfies x^2 + y^2 = r(z)^2, where the radius changes linearly with k = dr/dz.
+        let k = (self.top_radius - self.base_radius) / self.height;
+        let r_o = self.radius_at(o.z);
+        let a = d.x * d.x + d.y * d.y - k * k * d.z * d.z;
+        let b = o.x * d.x + o.y * d.y - k * r_o * d.z;
+        let c = o.x * o.x + o.y * o.y - r_o * r_o;
+        let on_side = |t: Float| {
+            let z = o.z + d.z * t;
+            (0.0..=self.height).contains(&z) && self.radius_at(z) >= 0.0
+        };
+        if a.abs() > 1e-8 {
+            let discriminant = b * b - a * c;
+            if discriminant > 0.0 {
+                let d_sqrt = discriminant.sqrt();
+                for t in [(-b - d_sqrt) / a, (-b + d_sqrt) / a] {
+                    if on_side(t) {
+                        consider(t, FrustumPart::Side);
+                    }
+                }
+            }
+        } else if b.abs() > 1e-8 {
+            // The ray is parallel to the slope of a cone, so it hits the side only once.
+            let t = -c / (2.0 * b);
+            if on_side(t) {
+                consider(t, FrustumPart::Side);
+            }
+        }
+
+        if self.capped && d.z.abs() > 1e-8 {
+            for (z, radius, part) in [
+                (0.0, self.base_radius, FrustumPart::Base),
+                (self.height, self.top_radius, FrustumPart::Top),
+            ] {
+                let t = (z - o.z) / d.z;
+                let p = o + d * t;
+                if p.x * p.x + p.y * p.y <= radius * radius {
+                    consider(t, part);
+                }
+            }
+        }
+        crossings
+    }
+
+    fn intersect<'a>(
+        &self,
+        h: &HitAttr,
+        reflection: &'a dyn Reflection,
+    ) -> Option<SurfaceInteraction<'a>> {
+        let (t, part) = self
+            .crossings(h)
+            .into_iter()
+            .filter(|&(t, _)| h.ray.contains(t))
+            .min_by(|a, b| a.0.total_cmp(&b.0))?;
+        let p = self.onb.to_local(h.ray.at(t) - self.base);
+        // The caps are parametrized like a Disk, with the larger radius.
+        let diameter = 2.0 * self.base_radius.max(self.top_radius);
+        let cap_uv = [0.5 + p.x / diameter, 0.5 + p.y / diameter];
+        let (uv, dpdu, dpdv) = match part {
+            FrustumPart::Side => {
+                // u goes around the axis, and v along it.
+                let k = (self.top_radius - self.base_radius) / self.height;
+                let phi = p.y.atan2(p.x);
+                (
+                    [phi / (2.0 * PI) + 0.5, p.z / self.height],
+                    Vec3::new(-p.y, p.x, 0.0) * (2.0 * PI),
+                    Vec3::new(k * phi.cos(), k * phi.sin(), 1.0) * self.height,
+                )
+            }
+            FrustumPart::Base | FrustumPart::Top => (
+                cap_uv,
+                Vec3::new(diameter, 0.0, 0.0),
+                Vec3::new(0.0, diameter, 0.0),
+            ),
+        };
+        let outward = self.onb.to_world(self.local_normal(p, part));
+        // An open tube has no inside, so the normal faces the incoming ray.
+        let normal = if self.capped {
+            outward
+        } else {
+            facing(outward, h)
+        };
+        Some(
+            SurfaceInteraction::new(
                 t,
-                ray: r,
-                hitkind: HitKind::LastHit,
-            },
+                h,
+                normal,
+                uv,
+                (self.onb.to_world(dpdu), self.onb.to_world(dpdv)),
+                reflection,
+            )
+            .with_front_side(outward, h),
+        )
+    }
+
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        // An open tube has no inside.
+        if self.capped {
+            let direction = self.onb.to_local(h.ray.direction);
+            pair_crossings(
+                self.crossings(h)
+                    .into_iter()
+                    .map(|(t, part)| {
+                        let p = self.onb.to_local(h.ray.at(t) - self.base);
+                        (t, direction.dot(&self.local_normal(p, part)) < 0.0)
+                    })
+                    .collect(),
+            )
+        } else {
+            Vec::new()
         }
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = self.base_radius.max(self.top_radius);
+        Some(local_bounding_box(
+            &self.onb,
+            self.base,
+            Vec3::new(-r, -r, 0.0),
+            Vec3::new(r, r, self.height),
+        ))
+    }
+}
+
+/// Cylinder from `base` to `base + axis` with a given radius, and a given reflection.
+/// A capped cylinder is closed by disks at both ends, so Glass works with it.
+pub struct Cylinder<R: Reflection> {
+    frustum: Frustum,
+    reflection: R,
+}
+
+impl<R> Cylinder<R>
+where
+    R: Reflection,
+{
+    /// Panics if the axis has zero length.
+    pub fn new(base: Vec3, axis: Vec3, radius: Float, capped: bool, reflection: R) -> Self {
+        Cylinder {
+            frustum: Frustum::new(base, axis, radius, radius, capped),
+            reflection,
+        }
+    }
+}
+
+impl<R> Hittable for Cylinder<R>
+where
+    R: Reflection,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        self.frustum.intersect(h, &self.reflection)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.frustum.bounding_box()
+    }
+
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        self.frustum.get_intervals(h)
+    }
+}
+
+/// Cone from `base` to `base + axis`, with the radius changing linearly from `base_radius` to `top_radius`, and a given reflection.
+/// A `top_radius` of 0 gives a pointed cone. A capped cone is closed by disks at both ends, so Glass works with it.
+pub struct Cone<R: Reflection> {
+    frustum: Frustum,
+    reflection: R,
+}
+
+impl<R> Cone<R>
+where
+    R: Reflection,
+{
+    /// Panics if the axis has zero length.
+    pub fn new(
+        base: Vec3,
+        axis: Vec3,
+        base_radius: Float,
+        top_radius: Float,
+        capped: bool,
+        reflection: R,
+    ) -> Self {
+        Cone {
+            frustum: Frustum::new(base, axis, base_radius, top_radius, capped),
+            reflection,
+        }
+    }
+}
+
+impl<R> Hittable for Cone<R>
+where
+    R: Reflection,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        self.frustum.intersect(h, &self.reflection)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.frustum.bounding_box()
+    }
+
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        self.frustum.get_intervals(h)
+    }
+}
+
+/// Torus around `center`, with the ring lying in the plane perpendicular to `axis`, and a given reflection.
+/// `major_radius` is the radius of the ring and `minor_radius` the radius of the tube.
+pub struct Torus<R: Reflection> {
+    center: Vec3,
+    onb: Onb,
+    major_radius: Float,
+    minor_radius: Float,
+    reflection: R,
+}
+
+impl<R> Torus<R>
+where
+    R: Reflection,
+{
+    pub fn new(
+        center: Vec3,
+        axis: Vec3,
+        major_radius: Float,
+        minor_radius: Float,
+        reflection: R,
+    ) -> Self {
+        Torus {
+            center,
+            onb: Onb::from_w(axis),
+            major_radius,
+            minor_radius,
+            reflection,
+        }
+    }
+
+    fn local_bounds(&self) -> (Vec3, Vec3) {
+        let r = self.major_radius + self.minor_radius;
+        (
+            Vec3::new(-r, -r, -self.minor_radius),
+            Vec3::new(r, r, self.minor_radius),
+        )
+    }
+
+    /// Returns the distances of every point where the line through the ray crosses the surface, in ascending order.
+    fn crossings(&self, h: &HitAttr) -> Vec<Float> {
+        let mut local = h.ray;
+        local.origin = self.onb.to_local(h.ray.origin - self.center);
+        local.direction = self.onb.to_local(h.ray.direction);
+        // Solving the quartic close to the torus keeps the coefficients well conditioned.
+        let (min, max) = self.local_bounds();
+        let (t_0, t_1) = match Aabb::new(min, max).intersect_range(
+            &local,
+            Float::NEG_INFINITY,
+            Float::INFINITY,
+        ) {
+            Some(range) => range,
+            None => return Vec::new(),
+        };
+        let o = local.at(t_0);
+        let d = local.direction;
+        let (ox, oy, oz) = (o.x as f64, o.y as f64, o.z as f64);
+        let (dx, dy, dz) = (d.x as f64, d.y as f64, d.z as f64);
+        let major_2 = (self.major_radius as f64).powi(2);
+        let minor_2 = (self.minor_radius as f64).powi(2);
+
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + y^2), expanded for p = o + t d with |d| = 1.
+        let e = ox * ox + oy * oy + oz * oz - major_2 - minor_2;
+        let f = ox * dx + oy * dy + oz * dz;
+        let coefficients = [
+            e * e + 4.0 * major_2 * (oz * oz - minor_2),
+            4.0 * f * e + 8.0 * major_2 * oz * dz,
+            2.0 * e + 4.0 * f * f + 4.0 * major_2 * dz * dz,
+            4.0 * f,
+            1.0,
+        ];
+        polynomial_roots(&coefficients, 0.0, (t_1 - t_0) as f64)
+            .into_iter()
+            .map(|s| t_0 + s as Float)
+            .collect()
+    }
+}
+
+impl<R> Hittable for Torus<R>
+where
+    R: Reflection,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let t = self.crossings(h).into_iter().find(|&t| h.ray.contains(t))?;
+        let p = self.onb.to_local(h.ray.at(t) - self.center);
+        // u goes around the axis, and v around the tube, starting at the outer equator.
+        let phi = p.y.atan2(p.x);
+        let ring = Vec3::new(phi.cos(), phi.sin(), 0.0);
+        let tube = p - ring * self.major_radius;
+        let psi = tube.z.atan2(tube.dot(&ring));
+        // The normal points away from the closest point on the ring.
+        let normal = tube.normalize();
+        let dpdu = Vec3::new(-p.y, p.x, 0.0) * (2.0 * PI);
+        let dpdv = (Vec3::new(0.0, 0.0, psi.cos()) - ring * psi.sin())
+            * (2.0 * PI * self.minor_radius);
+        Some(SurfaceInteraction::new(
+            t,
+            h,
+            self.onb.to_world(normal),
+            [phi / (2.0 * PI) + 0.5, psi / (2.0 * PI) + 0.5],
+            (self.onb.to_world(dpdu), self.onb.to_world(dpdv)),
+            &self.reflection,
+        ))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let (min, max) = self.local_bounds();
+        Some(local_bounding_box(&self.onb, self.center, min, max))
+    }
+
+    // The crossings are sign changes of the implicit function, so they alternate between entering and leaving.
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        pair_crossings(
+            self.crossings(h)
+                .into_iter()
+                .enumerate()
+                .map(|(i, t)| (t, i % 2 == 0))
+                .collect(),
+        )
+    }
+}
+
+/// Places an object in the scene with an affine transform(e.g. translation, rotation, scale).
+/// Wrap the object in an Arc to render the same object several times without copying it.
+/// The object is intersected in its local frame, and the hit is moved back into the world.
+pub struct Instance<H: Hittable> {
+    object: H,
+    transform: Transform,
+}
+
+impl<H> Instance<H>
+where
+    H: Hittable,
+{
+    /// `transform` maps the local frame of the object to the world.
+    pub fn new(object: H, transform: Transform) -> Self {
+        Instance { object, transform }
+    }
+
+    /// Moves the ray into the local frame of the object.
+    /// The direction is normalized again, so local distances are world distances times the returned scale.
+    fn local(&self, h: &HitAttr) -> (HitAttr, Float) {
+        let inverse = self.transform.inverse();
+        let direction = inverse.vector(h.ray.direction);
+        let scale = direction.mag();
+        let mut local = *h;
+        local.ray.origin = inverse.point(h.ray.origin);
+        local.ray.direction = direction * (1.0 / scale);
+        local.ray.t_min = h.ray.t_min * scale;
+        local.ray.t_max = h.ray.t_max * scale;
+        (local, scale)
+    }
+}
+
+impl<H> Hittable for Instance<H>
+where
+    H: Hittable,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let (local, scale) = self.local(h);
+        let si = self.object.intersect(&local)?;
+        let t = si.t / scale;
+        Some(si.transform(&self.transform, t))
+    }
+
+    fn occluded(&self, h: &HitAttr) -> bool {
+        self.object.occluded(&self.local(h).0)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let aabb = self.object.bounding_box()?;
+        let mut corners = Vec::with_capacity(8);
+        for x in [aabb.min.x, aabb.max.x] {
+            for y in [aabb.min.y, aabb.max.y] {
+                for z in [aabb.min.z, aabb.max.z] {
+                    corners.push(self.transform.point(Vec3::new(x, y, z)));
+                }
+            }
+        }
+        Some(Aabb::from_points(&corners))
+    }
+
+    fn get_intervals(&self, h: &HitAttr) -> Vec<(Float, Float)> {
+        let (local, scale) = self.local(h);
+        self.object
+            .get_intervals(&local)
+            .into_iter()
+            .map(|(t_in, t_out)| (t_in / scale, t_out / scale))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Ray;
+    use crate::reflection::Diffuse;
+
+    fn hit(origin: Vec3, direction: Vec3) -> HitAttr {
+        HitAttr {
+            t: 0.0,
+            ray: Ray::new(origin, direction),
+        }
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).mag() < 1e-5, "{a:?} is not {b:?}");
+    }
+
+    #[test]
+    fn sphere_interaction() {
+        let sphere = Sphere::new(Vec3::zero(), 2.0, Diffuse::new(Vec3::one()));
+        let si = sphere
+            .intersect(&hit(Vec3::new(0.0, -5.0, 0.0), Vec3::new(0.0, 1.0, 0.0)))
+            .unwrap();
+        assert!((si.t - 3.0).abs() < 1e-5);
+        assert_near(si.p, Vec3::new(0.0, -2.0, 0.0));
+        assert_near(si.normal, Vec3::new(0.0, -1.0, 0.0));
+        assert!(si.front_face);
+        assert!((si.uv[0] - 0.25).abs() < 1e-5 && (si.uv[1] - 0.5).abs() < 1e-5);
+        // The tangents lie in the tangent plane, and span it with the orientation of the normal.
+        assert!(si.dpdu.dot(&si.normal).abs() < 1e-5);
+        assert!(si.dpdv.dot(&si.normal).abs() < 1e-5);
+        assert!(si.dpdu.cross(&si.dpdv).dot(&si.normal) > 0.0);
+        // The scale of the tangents is the size of the sphere along u and v.
+        assert!((si.dpdu.mag() - 4.0 * PI).abs() < 1e-4);
+        assert!((si.dpdv.mag() - 2.0 * PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sphere_hit_from_inside() {
+        let sphere = Sphere::new(Vec3::zero(), 2.0, Diffuse::new(Vec3::one()));
+        let si = sphere
+            .intersect(&hit(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0)))
+            .unwrap();
+        assert!((si.t - 2.0).abs() < 1e-5);
+        assert_near(si.normal, Vec3::new(0.0, 0.0, 1.0));
+        assert!(!si.front_face);
+        assert!((si.uv[1] - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn two_sided_front_face() {
+        let quad = Quad::new(
+            Vec3::new(-1.0, -1.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            Diffuse::new(Vec3::one()),
+        );
+        let above = quad
+            .intersect(&hit(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert!(above.front_face);
+        assert_near(above.normal, Vec3::new(0.0, 0.0, 1.0));
+        // From behind, the normal still faces the ray, but the back side is hit.
+        let below = quad
+            .intersect(&hit(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0)))
+            .unwrap();
+        assert!(!below.front_face);
+        assert_near(below.normal, Vec3::new(0.0, 0.0, -1.0));
+    }
 }
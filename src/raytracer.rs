@@ -1,6 +1,8 @@
+use crate::bvh::HittableList;
+use crate::camera::Camera;
 use crate::math::{self, rand, Float, Ray, Vec3};
 use crate::object::Hittable;
-use crate::reflection::{HitAttr, HitKind};
+use crate::reflection::HitAttr;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -27,7 +29,7 @@ impl Raytracer {
         }
     }
 
-    pub fn run(&self, objects: Vec<Box<dyn Hittable + Send + Sync>>) -> Vec<Vec3> {
+    pub fn run(&self, camera: &Camera, objects: Vec<Box<dyn Hittable + Send + Sync>>) -> Vec<Vec3> {
         // Timer for benchmark
         let timer = std::time::Instant::now();
         let mut thread_pool = Vec::new();
@@ -37,7 +39,7 @@ impl Raytracer {
         let mutex_pb = Arc::new(Mutex::new(pb));
         let pixel_buffer = vec![Vec3::zero(); self.image_height * self.image_width];
         let mutex_pixelbuffer = Arc::new(Mutex::new(pixel_buffer));
-        let arc_objects = Arc::new(objects);
+        let arc_objects = Arc::new(HittableList::new(objects));
         let image_width = self.image_width;
         let image_height = self.image_height;
         let sample_num = self.sample_num / self.thread_num;
@@ -46,39 +48,31 @@ impl Raytracer {
             let mutex_pb = Arc::clone(&mutex_pb);
             let mutex_pixelbuffer = Arc::clone(&mutex_pixelbuffer);
             let arc_objects = Arc::clone(&arc_objects);
+            let camera = *camera;
             thread_pool.push(thread::spawn(move || {
-                let viewport_width = 2.0;
-                let viewport_height = 2.0;
-                let focal_length = 2.0;
-                let origin = Vec3::new(0.0, -1.0, 0.0);
-                let horizontal = Vec3::new(viewport_width, 0.0, 0.0);
-                let vertical = Vec3::new(0.0, 0.0, viewport_height);
-                let lower_left_corner =
-                    origin - horizontal * 0.5 - vertical * 0.5 + Vec3::new(0.0, focal_length, 0.0);
+                let rb = RayBouncer::new(1000, &arc_objects);
                 for y in 0..image_height {
                     for x in 0..image_width {
                         let mut accum_color = Vec3::zero();
                         for _ in 0..sample_num {
-                            let r = Ray::new(origin, {
+                            let r = {
                                 let rand_x = rand() - 0.5;
                                 let rand_y = rand() - 0.5;
                                 let u = (x as Float + rand_x) / image_width as Float;
                                 let v = 1.0 - (y as Float + rand_y) / image_height as Float;
-                                lower_left_corner + horizontal * u + vertical * v - origin
-                            });
-                            let rb = RayBouncer::new(r, 1000, &*arc_objects);
-                            let color = if let Some(h) = rb.last() {
-                                h.ray.color
-                            } else {
-                                Vec3::sky_color()
+                                camera.get_ray(u, v)
                             };
-                            accum_color += color;
+                            // Points outside of the projection(e.g. the corners of a fisheye image) stay black.
+                            let r = match r {
+                                Some(r) => r,
+                                None => continue,
+                            };
+                            accum_color += rb.radiance(r);
                         }
                         let color = accum_color * (1.0 / (sample_num as Float));
                         {
                             let mut pixel_buffer = mutex_pixelbuffer.lock().unwrap();
-                            pixel_buffer[y * image_width + x] =
-                                pixel_buffer[y * image_width + x] + color;
+                            pixel_buffer[y * image_width + x] += color;
                         }
                         mutex_pb.lock().unwrap().inc(1);
                     }
@@ -89,11 +83,8 @@ impl Raytracer {
             j.join().unwrap();
         }
         let mut pixel_buffer = mutex_pixelbuffer.lock().unwrap();
-        for x in 0..image_height {
-            for y in 0..image_width {
-                pixel_buffer[y * image_width + x] =
-                    pixel_buffer[y * image_width + x] * (1.0 / (self.thread_num as Float));
-            }
+        for pixel in pixel_buffer.iter_mut() {
+            *pixel = *pixel * (1.0 / (self.thread_num as Float));
         }
         let res = pixel_buffer.clone();
         // Timer for benchmark
@@ -107,94 +98,44 @@ impl Raytracer {
     }
 }
 
-/// RayBouncer is an iterator that iterates bounces the ray until it hits nothing.
-/// It returns the HitAttr of the last bounce every time it is called.
-/// If the ray bounces nothing, it returns None.
+/// RayBouncer bounces rays through the scene until they hit nothing, are absorbed or reach the maximum depth.
+/// The throughput of a path is the fraction of light that its bounces so far let through towards the camera.
 struct RayBouncer<'a> {
-    depth: usize,
     max_depth: usize,
-    objects: &'a Vec<Box<dyn Hittable + Send + Sync>>,
-    hitattr: Option<HitAttr>,
+    objects: &'a HittableList,
 }
 
 impl<'a> RayBouncer<'a> {
-    /// Constructs a new RayBouncer. The ray bounces until it hits nothing or the maximum depth.
-    pub fn new(
-        ray: Ray,
-        max_depth: usize,
-        objects: &'a Vec<Box<dyn Hittable + Send + Sync>>,
-    ) -> Self {
-        Self {
-            depth: 0,
-            max_depth,
-            objects,
-            hitattr: Some(HitAttr {
-                t: 0.0,
-                ray,
-                hitkind: HitKind::NormalHit,
-            }),
-        }
+    pub fn new(max_depth: usize, objects: &'a HittableList) -> Self {
+        Self { max_depth, objects }
     }
 
-    /// Returns the last hit attribute. After the last hit(e.g. Hits the sky), returns None.
-    fn ray_increment(
-        h: &HitAttr,
-        objects: &Vec<Box<dyn Hittable + Send + Sync>>,
-    ) -> Option<HitAttr> {
-        // Returns None when the ray hits the sky.
-        if let HitKind::LastHit = h.hitkind {
-            return None;
-        }
-        // let mut closest_hitattr: Option<HitAttr> = None;
-        let mut closest_dist: Option<(Float, usize)> = None;
-        for (i, object) in objects.iter().enumerate() {
-            let hit_dist = object.get_intersect(h);
-            
-            if let Some(real_closest_dist) = closest_dist {
-                if let Some(hit_dist) = hit_dist {
-                    if hit_dist < real_closest_dist.0{
-                        closest_dist = Some((hit_dist, i));
-                    }
-                }
-            } else if let Some(hit_dist) = hit_dist {
-                closest_dist = Some((hit_dist, i));
-            }
-        }
-        if let Some((closest_dist, closest_index)) = closest_dist {
-            let closest_object = &objects[closest_index];
-            let p = h.ray.at(closest_dist);
-            let mut next_hitattr = closest_object.reflect(
-                closest_dist, 
-                h.ray.at(closest_dist), 
-                closest_object.get_normal(h, p), 
-                h
-            );
+    /// Returns the light arriving along the ray, accumulated over the emitters and the sky the path hits.
+    pub fn radiance(&self, ray: Ray) -> Vec3 {
+        let mut h = HitAttr { t: 0.0, ray };
+        let mut throughput = Vec3::one();
+        let mut radiance = Vec3::zero();
+        for _ in 0..self.max_depth {
+            let si = match self.objects.intersect(&h) {
+                Some(si) => si,
+                None => return radiance + throughput.mul(&Vec3::sky_color()),
+            };
+            let wo = -h.ray.direction;
+            radiance += throughput.mul(&si.reflection.emitted(&si, wo));
+            let sample = match si.reflection.sample(&si, wo) {
+                Some(sample) => sample,
+                None => return radiance,
+            };
+            throughput = throughput.mul(&sample.weight);
             // Make sure to use math::EPSILON defined in this crate, not std::f32::EPSILON
-            // Add a small epsilon to avoid shadow acne
-            next_hitattr.ray.origin = p + next_hitattr.ray.direction * math::EPSILON;
-            return Some(next_hitattr);
-        }
-        None
-    }
-}
-
-impl<'a> Iterator for RayBouncer<'a> {
-    type Item = HitAttr;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.depth < self.max_depth {
-            self.depth += 1;
-            if let Some(hitattr) = self.hitattr {
-                if let Some(next_hitattr) = RayBouncer::ray_increment(&hitattr, self.objects) {
-                    self.hitattr = Some(next_hitattr);
-                    Some(next_hitattr)
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        } else {
-            None
+            // Ignore hits closer than a small epsilon to avoid shadow acne
+            h = HitAttr {
+                t: si.t,
+                ray: Ray::new(si.p, sample.wi)
+                    .with_time(h.ray.time)
+                    .with_interval(math::EPSILON, Float::INFINITY),
+            };
         }
+        radiance
     }
 }
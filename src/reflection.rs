@@ -1,4 +1,7 @@
-use crate::math::{self, Float, Ray, Vec3};
+use crate::math::{self, Float, Onb, Ray, Vec3, PI};
+use crate::microfacet::{self, fresnel_conductor_rgb, fresnel_dielectric, fresnel_schlick, Ggx};
+use crate::object::SurfaceInteraction;
+use crate::texture::Texture;
 
 #[derive(Clone, Copy)]
 /// The hit attribute of a ray-object intersection. 
@@ -6,108 +9,427 @@ use crate::math::{self, Float, Ray, Vec3};
 pub struct HitAttr {
     pub t: Float,
     pub ray: Ray,
-    pub hitkind: HitKind,
 }
 
-#[derive(Clone, Copy)]
-/// The kind of a ray-object intersection. 
-/// NormalHit indicates that the ray bounces off or penetrates the object. 
-/// LastHit indicates that the ray terminates there. 
-/// Used in HitAttr.
-pub enum HitKind {
-    NormalHit,
-    LastHit,
+/// A direction sampled by a Reflection, along which light arrives and scatters towards the viewer.
+pub struct BsdfSample {
+    /// Direction the light arrives from, pointing away from the surface.
+    pub wi: Vec3,
+    /// The BSDF times the cosine divided by the pdf, i.e. the factor the light arriving from wi is scaled by.
+    pub weight: Vec3,
+    /// Probability density of choosing wi per unit solid angle.
+    /// For specular samples, it is the probability of choosing the delta distribution instead.
+    pub pdf: Float,
+    /// Whether wi was chosen from a delta distribution(e.g. Mirror, Glass), which eval and pdf are zero for.
+    pub specular: bool,
 }
 
-/// Similar to HitAttr, but used to pass information within the reflection object
-pub enum Hit {
-    NormalHit(Ray),
-    LastHit(Ray),
+impl BsdfSample {
+    /// A sample of a delta distribution, which was chosen with the given probability.
+    pub fn specular(wi: Vec3, weight: Vec3, pdf: Float) -> Self {
+        BsdfSample {
+            wi,
+            weight,
+            pdf,
+            specular: true,
+        }
+    }
+
+    /// Completes a direction sampled from a reflection without delta distributions with its pdf and weight.
+    /// Returns None if the reflection does not scatter light from wi towards wo.
+    pub fn from_direction<R>(
+        reflection: &R,
+        si: &SurfaceInteraction,
+        wo: Vec3,
+        wi: Vec3,
+    ) -> Option<Self>
+    where
+        R: Reflection + ?Sized,
+    {
+        let pdf = reflection.pdf(si, wo, wi);
+        if pdf <= 0.0 || !pdf.is_finite() {
+            return None;
+        }
+        Some(BsdfSample {
+            wi,
+            weight: reflection.eval(si, wo, wi) * (1.0 / pdf),
+            pdf,
+            specular: false,
+        })
+    }
 }
 
 /// Needs to be implemented to be rendered by the raytracer.
+/// Describes how light scatters at the point `si` as a BSDF(bidirectional scattering distribution function).
+/// `wo` is the direction towards the viewer and `wi` the direction the light arrives from.
+/// Both are normalized, in world space and point away from the surface.
 pub trait Reflection {
-    fn get_reflection(&self, p: Vec3, normal: Vec3, h: &HitAttr) -> Hit;
+    /// Returns the BSDF times the cosine between wi and the shading normal,
+    /// i.e. the fraction of the light arriving from wi that scatters towards wo.
+    /// Delta distributions(e.g. Mirror) are zero everywhere.
+    fn eval(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3;
+    /// Samples a direction the light arrives from, or returns None if the light is absorbed.
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample>;
+    /// Returns the probability density of `sample` choosing wi, per unit solid angle.
+    fn pdf(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float;
+    /// Returns the light the surface emits towards wo.
+    fn emitted(&self, _si: &SurfaceInteraction, _wo: Vec3) -> Vec3 {
+        Vec3::zero()
+    }
 }
 
-pub fn simple_specular_reflection(color: &Vec3, p: Vec3, normal: Vec3, h: &HitAttr) -> Hit {
-    Hit::NormalHit(Ray {
-        origin: p,
-        direction: normal * -2.0 * normal.dot(&h.ray.direction) + h.ray.direction,
-        color: h.ray.color.mul(color),
-    })
+/// Returns the shading normal flipped to the side of wo.
+fn facing_normal(si: &SurfaceInteraction, wo: Vec3) -> Vec3 {
+    if si.shading_normal.dot(&wo) < 0.0 {
+        -si.shading_normal
+    } else {
+        si.shading_normal
+    }
 }
 
-/// Diffuses incoming ray uniformly over all directions.
-pub struct Diffuse {
-    color: Vec3,
+/// A Lambertian surface, which reflects light equally in all directions.
+/// Directions are sampled proportional to the cosine to the normal, so every sample carries the same weight.
+pub struct Diffuse<T: Texture<Vec3>> {
+    color: T,
 }
 
-impl Diffuse {
-    pub fn new(color: Vec3) -> Self {
+impl<T> Diffuse<T>
+where
+    T: Texture<Vec3>,
+{
+    pub fn new(color: T) -> Self {
         Self { color }
     }
 }
 
-impl Reflection for Diffuse {
-    fn get_reflection(&self, p: Vec3, normal: Vec3, h: &HitAttr) -> Hit {
-        Hit::NormalHit(Ray {
-            origin: p,
-            color: h.ray.color.mul(&self.color),
-            direction: normal.random_diffusion(),
+impl<T> Reflection for Diffuse<T>
+where
+    T: Texture<Vec3>,
+{
+    fn eval(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3 {
+        let cos = wi.dot(&facing_normal(si, wo));
+        if cos <= 0.0 {
+            return Vec3::zero();
+        }
+        self.color.value(si.uv, si.p) * (cos / PI)
+    }
+
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let direction = Vec3::random_cosine_direction();
+        Some(BsdfSample {
+            wi: Onb::from_w(facing_normal(si, wo)).to_world(direction),
+            weight: self.color.value(si.uv, si.p),
+            pdf: direction.z / PI,
+            specular: false,
         })
     }
+
+    fn pdf(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float {
+        wi.dot(&facing_normal(si, wo)).max(0.0) / PI
+    }
 }
 
 /// A mirror reflection.
-pub struct Mirror {
-    color: Vec3,
+pub struct Mirror<T: Texture<Vec3>> {
+    color: T,
 }
 
-impl Mirror {
-    pub fn new(color: Vec3) -> Self {
+impl<T> Mirror<T>
+where
+    T: Texture<Vec3>,
+{
+    pub fn new(color: T) -> Self {
         Self { color }
     }
 }
 
-impl Reflection for Mirror {
-    fn get_reflection(&self, p: Vec3, normal: Vec3, h: &HitAttr) -> Hit {
-        simple_specular_reflection(&self.color, p, normal, h)
+impl<T> Reflection for Mirror<T>
+where
+    T: Texture<Vec3>,
+{
+    fn eval(&self, _si: &SurfaceInteraction, _wo: Vec3, _wi: Vec3) -> Vec3 {
+        Vec3::zero()
+    }
+
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let wi = microfacet::reflect(wo, si.shading_normal);
+        Some(BsdfSample::specular(wi, self.color.value(si.uv, si.p), 1.0))
+    }
+
+    fn pdf(&self, _si: &SurfaceInteraction, _wo: Vec3, _wi: Vec3) -> Float {
+        0.0
     }
 }
 
-/// A Light Source that emits light in all directions uniformly.
-pub struct DiffusedLightSource {
-    color: Vec3,
+/// A metal with a rough surface, e.g. brushed or satin metals.
+/// The mirror direction is perturbed randomly, more so with higher roughness.
+/// A roughness of 0 is the same as Mirror, and 1 is close to Diffuse.
+pub struct Metal<T: Texture<Vec3>> {
+    color: T,
+    roughness: Float,
 }
 
-impl DiffusedLightSource {
-    pub fn new(color: Vec3) -> Self {
-        Self { color }
+impl<T> Metal<T>
+where
+    T: Texture<Vec3>,
+{
+    pub fn new(color: T, roughness: Float) -> Self {
+        Self {
+            color,
+            roughness: roughness.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Density of perturbing the mirror direction into wi, per unit solid angle.
+    /// The perturbed points are uniformly distributed on a sphere with the radius roughness around the mirror direction,
+    /// and the line along wi passes through one or two of them.
+    fn perturbation_pdf(&self, mirror: Vec3, wi: Vec3) -> Float {
+        let r = self.roughness;
+        let cos = wi.dot(&mirror);
+        let discriminant = cos * cos - 1.0 + r * r;
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let root = discriminant.sqrt();
+        // A point at distance t contributes its density on the sphere 1 / (4 pi r^2),
+        // projected onto the unit sphere by t^2 / cos, where cos = root / r is the angle to the sphere normal.
+        let t_2: Float = [cos - root, cos + root]
+            .iter()
+            .filter(|&&t| t > 0.0)
+            .map(|t| t * t)
+            .sum();
+        t_2 / (4.0 * PI * r * root)
     }
 }
 
-impl Reflection for DiffusedLightSource {
-    fn get_reflection(&self, p: Vec3, _normal: Vec3, h: &HitAttr) -> Hit {
-        Hit::LastHit(Ray {
-            origin: p,
-            direction: Vec3::new(0.0, 0.0, 0.0),
-            color: h.ray.color.div(&Vec3::sky_color()).mul(&self.color),
+impl<T> Reflection for Metal<T>
+where
+    T: Texture<Vec3>,
+{
+    fn eval(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3 {
+        self.color.value(si.uv, si.p) * self.pdf(si, wo, wi)
+    }
+
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let normal = facing_normal(si, wo);
+        let mirror = microfacet::reflect(wo, normal);
+        let color = self.color.value(si.uv, si.p);
+        if self.roughness == 0.0 {
+            return Some(BsdfSample::specular(mirror, color, 1.0));
+        }
+        let wi = (mirror + Vec3::random_in_unit_sphere() * self.roughness).normalize();
+        // Directions perturbed below the surface are absorbed.
+        if wi.dot(&normal) <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            wi,
+            weight: color,
+            pdf: self.perturbation_pdf(mirror, wi),
+            specular: false,
         })
     }
+
+    fn pdf(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float {
+        let normal = facing_normal(si, wo);
+        if self.roughness == 0.0 || wi.dot(&normal) <= 0.0 {
+            return 0.0;
+        }
+        self.perturbation_pdf(microfacet::reflect(wo, normal), wi)
+    }
+}
+
+/// A physically based metal: a GGX microfacet surface with the Fresnel reflectance of a complex index of refraction.
+/// Presets with measured indices of refraction are provided for common metals.
+pub struct Conductor {
+    eta: Vec3,
+    k: Vec3,
+    ggx: Ggx,
+}
+
+impl Conductor {
+    /// `eta` and `k` are the real and imaginary parts of the index of refraction for red, green and blue.
+    /// A roughness of 0 is a perfect mirror.
+    pub fn new(eta: Vec3, k: Vec3, roughness: Float) -> Self {
+        Self {
+            eta,
+            k,
+            ggx: Ggx::from_roughness(roughness),
+        }
+    }
+
+    pub fn gold(roughness: Float) -> Self {
+        Self::new(
+            Vec3::new(0.143, 0.374, 1.442),
+            Vec3::new(3.983, 2.385, 1.603),
+            roughness,
+        )
+    }
+
+    pub fn copper(roughness: Float) -> Self {
+        Self::new(
+            Vec3::new(0.200, 0.924, 1.102),
+            Vec3::new(3.912, 2.452, 2.142),
+            roughness,
+        )
+    }
+
+    pub fn aluminium(roughness: Float) -> Self {
+        Self::new(
+            Vec3::new(1.657, 0.880, 0.521),
+            Vec3::new(9.224, 6.270, 4.837),
+            roughness,
+        )
+    }
+
+    pub fn silver(roughness: Float) -> Self {
+        Self::new(
+            Vec3::new(0.155, 0.117, 0.138),
+            Vec3::new(4.828, 3.122, 2.147),
+            roughness,
+        )
+    }
+}
+
+impl Reflection for Conductor {
+    fn eval(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3 {
+        let onb = Onb::from_w(facing_normal(si, wo));
+        let (wo, wi) = (onb.to_local(wo), onb.to_local(wi));
+        let m = (wo + wi).normalize();
+        fresnel_conductor_rgb(wo.dot(&m), self.eta, self.k) * self.ggx.eval_reflection(wo, wi)
+    }
+
+    // Samples a visible microfacet normal and reflects off it. The weight is the Fresnel reflectance
+    // times the fraction of the reflected light that is not shadowed by other microfacets.
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let onb = Onb::from_w(facing_normal(si, wo));
+        let wo = onb.to_local(wo);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let (wi, m) = self.ggx.sample_reflection(wo)?;
+        let weight = fresnel_conductor_rgb(wo.dot(&m), self.eta, self.k)
+            * (self.ggx.g2(wo, wi) / self.ggx.g1(wo));
+        Some(BsdfSample {
+            wi: onb.to_world(wi),
+            weight,
+            pdf: self.ggx.pdf_reflection(wo, wi),
+            specular: false,
+        })
+    }
+
+    fn pdf(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float {
+        let onb = Onb::from_w(facing_normal(si, wo));
+        self.ggx.pdf_reflection(onb.to_local(wo), onb.to_local(wi))
+    }
+}
+
+/// A Light Source that emits light in all directions uniformly, and absorbs all light that hits it.
+/// See Emissive for light sources that also reflect light.
+pub struct DiffusedLightSource<T: Texture<Vec3>> {
+    color: T,
+}
+
+impl<T> DiffusedLightSource<T>
+where
+    T: Texture<Vec3>,
+{
+    pub fn new(color: T) -> Self {
+        Self { color }
+    }
+}
+
+impl<T> Reflection for DiffusedLightSource<T>
+where
+    T: Texture<Vec3>,
+{
+    fn eval(&self, _si: &SurfaceInteraction, _wo: Vec3, _wi: Vec3) -> Vec3 {
+        Vec3::zero()
+    }
+
+    fn sample(&self, _si: &SurfaceInteraction, _wo: Vec3) -> Option<BsdfSample> {
+        None
+    }
+
+    fn pdf(&self, _si: &SurfaceInteraction, _wo: Vec3, _wi: Vec3) -> Float {
+        0.0
+    }
+
+    fn emitted(&self, si: &SurfaceInteraction, _wo: Vec3) -> Vec3 {
+        self.color.value(si.uv, si.p)
+    }
+}
+
+/// Adds emission to any reflection, e.g. glowing screens, neon signs or lamp shades.
+/// The surface emits light uniformly in all directions, and reflects light like the wrapped reflection.
+pub struct Emissive<R: Reflection, T: Texture<Vec3>> {
+    reflection: R,
+    emission: T,
+    one_sided: bool,
+}
+
+impl<R, T> Emissive<R, T>
+where
+    R: Reflection,
+    T: Texture<Vec3>,
+{
+    /// `emission` is in the units of DiffusedLightSource. Both sides of the surface emit.
+    pub fn new(reflection: R, emission: T) -> Self {
+        Self {
+            reflection,
+            emission,
+            one_sided: false,
+        }
+    }
+
+    /// Only emits from the front side of the surface(see SurfaceInteraction::front_face), e.g. screens.
+    pub fn one_sided(self) -> Self {
+        Self {
+            one_sided: true,
+            ..self
+        }
+    }
+}
+
+impl<R, T> Reflection for Emissive<R, T>
+where
+    R: Reflection,
+    T: Texture<Vec3>,
+{
+    fn eval(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3 {
+        self.reflection.eval(si, wo, wi)
+    }
+
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        self.reflection.sample(si, wo)
+    }
+
+    fn pdf(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float {
+        self.reflection.pdf(si, wo, wi)
+    }
+
+    fn emitted(&self, si: &SurfaceInteraction, wo: Vec3) -> Vec3 {
+        if self.one_sided && !si.front_face {
+            return self.reflection.emitted(si, wo);
+        }
+        self.emission.value(si.uv, si.p) + self.reflection.emitted(si, wo)
+    }
 }
 
 /// Refracts incoming ray in the direction of the normal. 
 /// Implementation is based on the Schlick approximation. 
 /// The incoming ray decays exponentially depending on the depth.
-pub struct Glass {
-    color: Vec3,
+pub struct Glass<T: Texture<Vec3>> {
+    color: T,
     refraction_index: Float,
     r_0: Float,
 }
 
-impl Glass {
-    pub fn new(color: Vec3, refraction_index: Float) -> Self {
+impl<T> Glass<T>
+where
+    T: Texture<Vec3>,
+{
+    pub fn new(color: T, refraction_index: Float) -> Self {
         Self {
             color,
             refraction_index,
@@ -116,64 +438,605 @@ impl Glass {
     }
 }
 
-fn snells_law(
-    normal: Vec3,
-    p: Vec3,
-    h: &HitAttr,
-    color: &Vec3,
-    refraction_index: Float,
-    cos_incidence_angle: Float,
-) -> Hit {
-    let det = cos_incidence_angle.powi(2) + refraction_index.powi(2) - 1.0;
-    if det > 0.0 {
-        let cos_refraction_angle = det.sqrt() / refraction_index;
-        let incidence_horizontal = h.ray.direction - normal * h.ray.direction.dot(&normal);
-        let refraction_direction =
-            normal * (-cos_refraction_angle) + incidence_horizontal * (1.0 / refraction_index);
-        Hit::NormalHit(Ray {
-            origin: p,
-            direction: refraction_direction,
-            color: *color,
-        })
-    } else {
-        simple_specular_reflection(color, p, normal, h)
+impl<T> Reflection for Glass<T>
+where
+    T: Texture<Vec3>,
+{
+    fn eval(&self, _si: &SurfaceInteraction, _wo: Vec3, _wi: Vec3) -> Vec3 {
+        Vec3::zero()
     }
-}
 
-impl Reflection for Glass {
-    fn get_reflection(&self, p: Vec3, normal: Vec3, h: &HitAttr) -> Hit {
-        let cos_incidence_angle = -normal.dot(&h.ray.direction);
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let normal = facing_normal(si, wo);
+        let cos_incidence_angle = wo.dot(&normal);
+        let entering = si.shading_normal.dot(&wo) > 0.0;
+        let glass_color = self.color.value(si.uv, si.p);
+        // Light leaving the glass has travelled through it, and decays depending on the depth.
+        let (refraction_index, color) = if entering {
+            (self.refraction_index, Vec3::one())
+        } else {
+            (
+                1.0 / self.refraction_index,
+                Vec3::one().exp_decay(si.t, &glass_color),
+            )
+        };
+        let mirror = microfacet::reflect(wo, normal);
         // Reflects randomly based on the Schlick approximation.
-        if math::rand() < self.r_0 + (1.0 - self.r_0) * (1.0 - cos_incidence_angle.abs()).powi(5) {
-            if cos_incidence_angle > 0.0 {
-                // Light gets reflected without entering the object.
-                simple_specular_reflection(&self.color, p, normal, h)
-            } else {
-                // Light gets reflected inside the object.
-                simple_specular_reflection(&h.ray.color.exp_decay(h.t, &self.color), p, -normal, h)
+        let reflectance = self.r_0 + (1.0 - self.r_0) * (1.0 - cos_incidence_angle).powi(5);
+        if math::rand() < reflectance {
+            let color = if entering { glass_color } else { color };
+            return Some(BsdfSample::specular(mirror, color, reflectance));
+        }
+        // Light enters or leaves the glass, unless it is totally reflected inside.
+        let wi = microfacet::refract(wo, normal, refraction_index).unwrap_or(mirror);
+        Some(BsdfSample::specular(wi, color, 1.0 - reflectance))
+    }
+
+    fn pdf(&self, _si: &SurfaceInteraction, _wo: Vec3, _wi: Vec3) -> Float {
+        0.0
+    }
+}
+
+/// An interface between two dielectrics with a GGX microfacet surface.
+/// Directions are in the local frame around the normal on the side of wo,
+/// and eta is the ratio of the index of refraction on the far side to the one on the side of wo.
+/// Implementation is based on Walter et al., "Microfacet Models for Refraction through Rough Surfaces".
+struct RoughInterface {
+    ggx: Ggx,
+    eta: Float,
+}
+
+impl RoughInterface {
+    /// Returns the microfacet normal that refracts wo into wi, or None if wi cannot be refracted into.
+    fn refraction_normal(&self, wo: Vec3, wi: Vec3) -> Option<Vec3> {
+        let m = (wo + wi * self.eta).normalize();
+        let m = if m.z < 0.0 { -m } else { m };
+        if wo.dot(&m) <= 0.0 || wi.dot(&m) >= 0.0 {
+            return None;
+        }
+        Some(m)
+    }
+
+    /// The BSDF times the cosine of wi, where reflection and refraction are weighted by the Fresnel reflectance.
+    fn eval(&self, wo: Vec3, wi: Vec3) -> Float {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        if wi.z > 0.0 {
+            let m = (wo + wi).normalize();
+            return fresnel_dielectric(wo.dot(&m), self.eta) * self.ggx.eval_reflection(wo, wi);
+        }
+        match self.refraction_normal(wo, wi) {
+            Some(m) => {
+                let (cos_o, cos_i) = (wo.dot(&m), wi.dot(&m));
+                let denominator = (cos_i + cos_o / self.eta).powi(2) * wo.z;
+                self.ggx.d(m)
+                    * (1.0 - fresnel_dielectric(cos_o, self.eta))
+                    * self.ggx.g2(wo, wi)
+                    * (cos_i * cos_o).abs()
+                    / denominator
             }
+            None => 0.0,
+        }
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> Float {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        if wi.z > 0.0 {
+            let m = (wo + wi).normalize();
+            return fresnel_dielectric(wo.dot(&m), self.eta) * self.ggx.pdf_reflection(wo, wi);
+        }
+        match self.refraction_normal(wo, wi) {
+            Some(m) => {
+                let (cos_o, cos_i) = (wo.dot(&m), wi.dot(&m));
+                let denominator = (cos_i + cos_o / self.eta).powi(2);
+                (1.0 - fresnel_dielectric(cos_o, self.eta))
+                    * self.ggx.pdf_visible_normal(wo, m)
+                    * cos_i.abs()
+                    / denominator
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Reflects or refracts wo at a sampled visible microfacet, picked randomly by the Fresnel reflectance.
+    /// Returns None if the sampled direction ends up on the wrong side of the surface.
+    fn sample(&self, wo: Vec3) -> Option<Vec3> {
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let m = self.ggx.sample_visible_normal(wo);
+        if math::rand() < fresnel_dielectric(wo.dot(&m), self.eta) {
+            Some(microfacet::reflect(wo, m)).filter(|wi| wi.z > 0.0)
+        } else {
+            microfacet::refract(wo, m, self.eta).filter(|wi| wi.z < 0.0)
+        }
+    }
+}
+
+/// Glass with a rough surface, e.g. frosted glass, sandblasted acrylic or rough plastics.
+/// Light is reflected or refracted at a randomly sampled GGX microfacet, with the exact Fresnel reflectance.
+/// Like Glass, the light decays exponentially depending on the depth inside the object.
+pub struct RoughGlass<T: Texture<Vec3>> {
+    color: T,
+    refraction_index: Float,
+    ggx: Ggx,
+}
+
+impl<T> RoughGlass<T>
+where
+    T: Texture<Vec3>,
+{
+    /// A roughness of 0 is a perfectly smooth surface like Glass.
+    pub fn new(color: T, refraction_index: Float, roughness: Float) -> Self {
+        Self {
+            color,
+            refraction_index,
+            ggx: Ggx::from_roughness(roughness),
+        }
+    }
+
+    /// Returns the local frame and the interface seen from wo, and the attenuation of the light.
+    fn interface(&self, si: &SurfaceInteraction, wo: Vec3) -> (Onb, RoughInterface, Vec3) {
+        // Light leaving the object has travelled through it, and decays depending on the depth.
+        let (eta, attenuation) = if si.shading_normal.dot(&wo) > 0.0 {
+            (self.refraction_index, Vec3::one())
+        } else {
+            (
+                1.0 / self.refraction_index,
+                Vec3::one().exp_decay(si.t, &self.color.value(si.uv, si.p)),
+            )
+        };
+        let interface = RoughInterface { ggx: self.ggx, eta };
+        (Onb::from_w(facing_normal(si, wo)), interface, attenuation)
+    }
+}
+
+impl<T> Reflection for RoughGlass<T>
+where
+    T: Texture<Vec3>,
+{
+    fn eval(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3 {
+        let (onb, interface, attenuation) = self.interface(si, wo);
+        attenuation * interface.eval(onb.to_local(wo), onb.to_local(wi))
+    }
+
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let (onb, interface, _) = self.interface(si, wo);
+        let wi = interface.sample(onb.to_local(wo))?;
+        BsdfSample::from_direction(self, si, wo, onb.to_world(wi))
+    }
+
+    fn pdf(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float {
+        let (onb, interface, _) = self.interface(si, wo);
+        interface.pdf(onb.to_local(wo), onb.to_local(wi))
+    }
+}
+
+/// A single material covering most assets, with the parameters of the Disney principled BRDF.
+/// Implementation is loosely based on Burley, "Physically Based Shading at Disney".
+/// It blends a diffuse base with a specular layer, a metal, a transmissive glass and a clear coat on top.
+/// Directions are sampled from one of these lobes, picked randomly by its share of the light.
+#[derive(Debug, Clone, Copy)]
+pub struct Principled<T: Texture<Vec3>, E: Texture<Vec3> = Vec3> {
+    base_color: T,
+    metallic: Float,
+    roughness: Float,
+    specular: Float,
+    clearcoat: Float,
+    clearcoat_roughness: Float,
+    sheen: Float,
+    transmission: Float,
+    refraction_index: Float,
+    emission: E,
+}
+
+/// Shares of the light of the lobes of a Principled, for a given direction towards the viewer. They sum up to 1.
+struct Lobes {
+    clearcoat: Float,
+    metal: Float,
+    glass: Float,
+    specular: Float,
+    diffuse: Float,
+}
+
+impl<T> Principled<T>
+where
+    T: Texture<Vec3>,
+{
+    /// A slightly glossy dielectric with the given base color.
+    /// The other parameters are set with the `with_` methods, and are clamped to [0, 1].
+    pub fn new(base_color: T) -> Self {
+        Principled {
+            base_color,
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.03,
+            sheen: 0.0,
+            transmission: 0.0,
+            refraction_index: 1.5,
+            emission: Vec3::zero(),
+        }
+    }
+}
+
+impl<T, E> Principled<T, E>
+where
+    T: Texture<Vec3>,
+    E: Texture<Vec3>,
+{
+    /// Blends from a dielectric at 0 to a metal reflecting the base color at 1.
+    pub fn with_metallic(self, metallic: Float) -> Self {
+        Principled {
+            metallic: metallic.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Roughness of the specular, metallic and transmissive lobes.
+    pub fn with_roughness(self, roughness: Float) -> Self {
+        Principled {
+            roughness: roughness.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Strength of the specular reflection of dielectrics.
+    /// The default of 0.5 is a reflectance of 4% at normal incidence, which fits most materials.
+    pub fn with_specular(self, specular: Float) -> Self {
+        Principled {
+            specular: specular.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// A glossy transparent layer on top of everything else, e.g. car paint or varnish.
+    pub fn with_clearcoat(self, clearcoat: Float, roughness: Float) -> Self {
+        Principled {
+            clearcoat: clearcoat.clamp(0.0, 1.0),
+            clearcoat_roughness: roughness.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Extra reflection of the diffuse base at grazing angles, e.g. cloth.
+    pub fn with_sheen(self, sheen: Float) -> Self {
+        Principled {
+            sheen: sheen.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Blends from an opaque dielectric at 0 to glass tinted by the base color at 1.
+    pub fn with_transmission(self, transmission: Float, refraction_index: Float) -> Self {
+        Principled {
+            transmission: transmission.clamp(0.0, 1.0),
+            refraction_index,
+            ..self
+        }
+    }
+
+    /// Light emitted by the surface, in the units of DiffusedLightSource. It can vary over the surface with a texture.
+    pub fn with_emission<F: Texture<Vec3>>(self, emission: F) -> Principled<T, F> {
+        Principled {
+            base_color: self.base_color,
+            metallic: self.metallic,
+            roughness: self.roughness,
+            specular: self.specular,
+            clearcoat: self.clearcoat,
+            clearcoat_roughness: self.clearcoat_roughness,
+            sheen: self.sheen,
+            transmission: self.transmission,
+            refraction_index: self.refraction_index,
+            emission,
+        }
+    }
+
+    /// Returns the shares of the lobes, where cos_o is the cosine between wo and the normal.
+    fn lobes(&self, cos_o: Float) -> Lobes {
+        // The clear coat reflects a part of the light before it reaches the layers below.
+        let clearcoat = self.clearcoat * fresnel_dielectric(cos_o, 1.5);
+        let base = 1.0 - clearcoat;
+        let dielectric = base * (1.0 - self.metallic) * (1.0 - self.transmission);
+        // Dielectrics reflect a part of the light specularly, and the rest enters and diffuses.
+        let specular = dielectric * fresnel_schlick(Vec3::one() * (0.08 * self.specular), cos_o).x;
+        Lobes {
+            clearcoat,
+            metal: base * self.metallic,
+            glass: base * (1.0 - self.metallic) * self.transmission,
+            specular,
+            diffuse: dielectric - specular,
+        }
+    }
+
+    /// Returns the local frame and the interface of the transmissive lobe seen from wo.
+    fn interface(&self, si: &SurfaceInteraction, wo: Vec3) -> (Onb, RoughInterface) {
+        let eta = if si.shading_normal.dot(&wo) > 0.0 {
+            self.refraction_index
+        } else {
+            1.0 / self.refraction_index
+        };
+        let interface = RoughInterface {
+            ggx: Ggx::from_roughness(self.roughness),
+            eta,
+        };
+        (Onb::from_w(facing_normal(si, wo)), interface)
+    }
+}
+
+impl<T, E> Reflection for Principled<T, E>
+where
+    T: Texture<Vec3>,
+    E: Texture<Vec3>,
+{
+    fn eval(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3 {
+        let base_color = self.base_color.value(si.uv, si.p);
+        let (onb, interface) = self.interface(si, wo);
+        let (wo, wi) = (onb.to_local(wo), onb.to_local(wi));
+        let lobes = self.lobes(wo.z);
+        // Light refracted through the glass is tinted by the base color.
+        let glass = interface.eval(wo, wi) * lobes.glass;
+        if wi.z <= 0.0 {
+            return base_color * glass;
+        }
+        let m = (wo + wi).normalize();
+        let clearcoat = Ggx::from_roughness(self.clearcoat_roughness).eval_reflection(wo, wi);
+        let glossy = interface.ggx.eval_reflection(wo, wi);
+        // The diffuse base reflects more at grazing angles with sheen.
+        let sheen = self.sheen * (1.0 - wi.dot(&m).clamp(0.0, 1.0)).powi(5);
+        let diffuse = (base_color + Vec3::one() * sheen) * (wi.z / PI);
+        Vec3::one() * (lobes.clearcoat * clearcoat + lobes.specular * glossy + glass)
+            + fresnel_schlick(base_color, wo.dot(&m)) * (lobes.metal * glossy)
+            + diffuse * lobes.diffuse
+    }
+
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let (onb, interface) = self.interface(si, wo);
+        let local_wo = onb.to_local(wo);
+        if local_wo.z <= 0.0 {
+            return None;
+        }
+        let lobes = self.lobes(local_wo.z);
+        let xi = math::rand();
+        let wi = if xi < lobes.clearcoat {
+            let clearcoat = Ggx::from_roughness(self.clearcoat_roughness);
+            clearcoat.sample_reflection(local_wo)?.0
+        } else if xi < lobes.clearcoat + lobes.metal + lobes.specular {
+            interface.ggx.sample_reflection(local_wo)?.0
+        } else if xi < 1.0 - lobes.diffuse {
+            interface.sample(local_wo)?
         } else {
-            if cos_incidence_angle > 0.0 {
-                // Light enters the glass.
-                snells_law(
-                    normal,
-                    p,
-                    h,
-                    &h.ray.color,
-                    self.refraction_index,
-                    cos_incidence_angle,
-                )
-            } else {
-                // Light leaves the glass.
-                snells_law(
-                    -normal,
-                    p,
-                    h,
-                    &h.ray.color.exp_decay(h.t, &self.color),
-                    1.0 / self.refraction_index,
-                    -cos_incidence_angle,
-                )
+            Vec3::random_cosine_direction()
+        };
+        BsdfSample::from_direction(self, si, wo, onb.to_world(wi))
+    }
+
+    fn pdf(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float {
+        let (onb, interface) = self.interface(si, wo);
+        let (wo, wi) = (onb.to_local(wo), onb.to_local(wi));
+        let lobes = self.lobes(wo.z);
+        let glass = interface.pdf(wo, wi) * lobes.glass;
+        if wi.z <= 0.0 {
+            return glass;
+        }
+        let clearcoat = Ggx::from_roughness(self.clearcoat_roughness).pdf_reflection(wo, wi);
+        let glossy = interface.ggx.pdf_reflection(wo, wi);
+        glass
+            + lobes.clearcoat * clearcoat
+            + (lobes.metal + lobes.specular) * glossy
+            + lobes.diffuse * wi.z / PI
+    }
+
+    fn emitted(&self, si: &SurfaceInteraction, _wo: Vec3) -> Vec3 {
+        self.emission.value(si.uv, si.p)
+    }
+}
+
+/// Blends two reflections, e.g. dirt over paint or moss on stone.
+/// A weight of 0 is only `a` and 1 is only `b`. The weight can vary over the surface with a texture.
+pub struct Mix<A: Reflection, B: Reflection, W: Texture<Float>> {
+    a: A,
+    b: B,
+    weight: W,
+}
+
+impl<A, B, W> Mix<A, B, W>
+where
+    A: Reflection,
+    B: Reflection,
+    W: Texture<Float>,
+{
+    pub fn new(a: A, b: B, weight: W) -> Self {
+        Self { a, b, weight }
+    }
+
+    fn weight(&self, si: &SurfaceInteraction) -> Float {
+        self.weight.value(si.uv, si.p).clamp(0.0, 1.0)
+    }
+}
+
+impl<A, B, W> Reflection for Mix<A, B, W>
+where
+    A: Reflection,
+    B: Reflection,
+    W: Texture<Float>,
+{
+    fn eval(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3 {
+        let w = self.weight(si);
+        self.a.eval(si, wo, wi) * (1.0 - w) + self.b.eval(si, wo, wi) * w
+    }
+
+    // One of the reflections is picked randomly by the weight.
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let w = self.weight(si);
+        let (sample, p) = if math::rand() < w {
+            (self.b.sample(si, wo)?, w)
+        } else {
+            (self.a.sample(si, wo)?, 1.0 - w)
+        };
+        if sample.specular {
+            return Some(BsdfSample::specular(
+                sample.wi,
+                sample.weight,
+                sample.pdf * p,
+            ));
+        }
+        BsdfSample::from_direction(self, si, wo, sample.wi)
+    }
+
+    fn pdf(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float {
+        let w = self.weight(si);
+        self.a.pdf(si, wo, wi) * (1.0 - w) + self.b.pdf(si, wo, wi) * w
+    }
+
+    fn emitted(&self, si: &SurfaceInteraction, wo: Vec3) -> Vec3 {
+        let w = self.weight(si);
+        self.a.emitted(si, wo) * (1.0 - w) + self.b.emitted(si, wo) * w
+    }
+}
+
+/// A transparent dielectric layer on top of a base reflection, e.g. car paint or varnished wood.
+/// The coat reflects a part of the light by its Fresnel reflectance, and the rest passes through it to the base and back.
+pub struct ClearCoat<R: Reflection> {
+    base: R,
+    refraction_index: Float,
+    ggx: Ggx,
+}
+
+impl<R> ClearCoat<R>
+where
+    R: Reflection,
+{
+    /// A roughness of 0 is a smooth, glossy coat. Varnishes typically have an index of refraction of about 1.5.
+    pub fn new(base: R, refraction_index: Float, roughness: Float) -> Self {
+        Self {
+            base,
+            refraction_index,
+            ggx: Ggx::from_roughness(roughness),
+        }
+    }
+
+    /// Fresnel reflectance of the coat for a direction with the given cosine to the normal.
+    fn reflectance(&self, cos: Float) -> Float {
+        fresnel_dielectric(cos.abs(), self.refraction_index)
+    }
+}
+
+impl<R> Reflection for ClearCoat<R>
+where
+    R: Reflection,
+{
+    fn eval(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Vec3 {
+        let onb = Onb::from_w(facing_normal(si, wo));
+        let (local_wo, local_wi) = (onb.to_local(wo), onb.to_local(wi));
+        let coat = self.reflectance(local_wo.z) * self.ggx.eval_reflection(local_wo, local_wi);
+        // Light reaching the base passes through the coat on the way in and out.
+        let transmittance =
+            (1.0 - self.reflectance(local_wo.z)) * (1.0 - self.reflectance(local_wi.z));
+        Vec3::one() * coat + self.base.eval(si, wo, wi) * transmittance
+    }
+
+    // The coat is picked randomly by its reflectance, otherwise the base.
+    fn sample(&self, si: &SurfaceInteraction, wo: Vec3) -> Option<BsdfSample> {
+        let onb = Onb::from_w(facing_normal(si, wo));
+        let local_wo = onb.to_local(wo);
+        let reflectance = self.reflectance(local_wo.z);
+        if math::rand() < reflectance {
+            let (wi, _) = self.ggx.sample_reflection(local_wo)?;
+            return BsdfSample::from_direction(self, si, wo, onb.to_world(wi));
+        }
+        let sample = self.base.sample(si, wo)?;
+        if sample.specular {
+            let transmittance = 1.0 - self.reflectance(onb.to_local(sample.wi).z);
+            return Some(BsdfSample::specular(
+                sample.wi,
+                sample.weight * transmittance,
+                sample.pdf * (1.0 - reflectance),
+            ));
+        }
+        BsdfSample::from_direction(self, si, wo, sample.wi)
+    }
+
+    fn pdf(&self, si: &SurfaceInteraction, wo: Vec3, wi: Vec3) -> Float {
+        let onb = Onb::from_w(facing_normal(si, wo));
+        let (local_wo, local_wi) = (onb.to_local(wo), onb.to_local(wi));
+        let reflectance = self.reflectance(local_wo.z);
+        reflectance * self.ggx.pdf_reflection(local_wo, local_wi)
+            + (1.0 - reflectance) * self.base.pdf(si, wo, wi)
+    }
+
+    fn emitted(&self, si: &SurfaceInteraction, wo: Vec3) -> Vec3 {
+        self.base.emitted(si, wo) * (1.0 - self.reflectance(si.shading_normal.dot(&wo)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that sampled directions have the weight eval / pdf, and the pdf given by Reflection::pdf.
+    fn check_samples<R: Reflection>(reflection: &R) {
+        let h = HitAttr {
+            t: 0.0,
+            ray: Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)),
+        };
+        let si = SurfaceInteraction::new(
+            1.0,
+            &h,
+            Vec3::new(0.0, 0.0, 1.0),
+            [0.5, 0.5],
+            (Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)),
+            reflection,
+        );
+        let near = |a: Float, b: Float| (a - b).abs() <= 1e-4 * a.abs().max(b.abs()).max(1.0);
+        for wo in [
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.6, 0.0, 0.8),
+            Vec3::new(0.0, -0.96, 0.28),
+            Vec3::new(0.3, 0.4, -0.866),
+        ] {
+            let wo = wo.normalize();
+            for _ in 0..200 {
+                let Some(sample) = reflection.sample(&si, wo) else {
+                    continue;
+                };
+                assert!(!sample.specular);
+                let (sampled_pdf, pdf) = (sample.pdf, reflection.pdf(&si, wo, sample.wi));
+                assert!(
+                    near(sampled_pdf, pdf),
+                    "sampled pdf {sampled_pdf} is not {pdf}"
+                );
+                let expected = reflection.eval(&si, wo, sample.wi) * (1.0 / pdf);
+                let direct = BsdfSample::from_direction(reflection, &si, wo, sample.wi).unwrap();
+                for weight in [sample.weight, direct.weight] {
+                    assert!(
+                        near(weight.x, expected.x)
+                            && near(weight.y, expected.y)
+                            && near(weight.z, expected.z),
+                        "weight {weight:?} is not {expected:?}"
+                    );
+                }
             }
-        }    
+        }
+    }
+
+    #[test]
+    fn diffuse_sample_weight() {
+        check_samples(&Diffuse::new(Vec3::new(0.8, 0.5, 0.2)));
+    }
+
+    #[test]
+    fn conductor_sample_weight() {
+        check_samples(&Conductor::gold(0.3));
+        check_samples(&Conductor::copper(0.05));
+    }
+
+    #[test]
+    fn rough_glass_sample_weight() {
+        check_samples(&RoughGlass::new(Vec3::one(), 1.5, 0.3));
     }
 }
@@ -0,0 +1,348 @@
+use crate::bvh::Aabb;
+use crate::math::{self, Float, Vec3};
+use crate::object::{tangent_frame, Hittable, SurfaceInteraction};
+use crate::reflection::{HitAttr, Reflection};
+
+/// A signed distance field: the distance from a point to the closest surface, negative inside the object.
+/// The distance may be underestimated(e.g. fractals), which only makes sphere tracing take more steps.
+/// Any `Fn(Vec3) -> Float` closure is a distance field.
+pub trait Sdf {
+    fn distance(&self, p: Vec3) -> Float;
+
+    fn union<B: Sdf>(self, other: B) -> SdfCombine<Self, B>
+    where
+        Self: Sized,
+    {
+        SdfCombine::new(self, other, SdfOperation::Union)
+    }
+
+    fn intersection<B: Sdf>(self, other: B) -> SdfCombine<Self, B>
+    where
+        Self: Sized,
+    {
+        SdfCombine::new(self, other, SdfOperation::Intersection)
+    }
+
+    fn difference<B: Sdf>(self, other: B) -> SdfCombine<Self, B>
+    where
+        Self: Sized,
+    {
+        SdfCombine::new(self, other, SdfOperation::Difference)
+    }
+
+    fn smooth_union<B: Sdf>(self, other: B, k: Float) -> SdfCombine<Self, B>
+    where
+        Self: Sized,
+    {
+        SdfCombine::new(self, other, SdfOperation::SmoothUnion(k))
+    }
+
+    fn smooth_intersection<B: Sdf>(self, other: B, k: Float) -> SdfCombine<Self, B>
+    where
+        Self: Sized,
+    {
+        SdfCombine::new(self, other, SdfOperation::SmoothIntersection(k))
+    }
+
+    fn smooth_difference<B: Sdf>(self, other: B, k: Float) -> SdfCombine<Self, B>
+    where
+        Self: Sized,
+    {
+        SdfCombine::new(self, other, SdfOperation::SmoothDifference(k))
+    }
+
+    fn blend<B: Sdf>(self, other: B, t: Float) -> SdfCombine<Self, B>
+    where
+        Self: Sized,
+    {
+        SdfCombine::new(self, other, SdfOperation::Blend(t))
+    }
+}
+
+impl<F> Sdf for F
+where
+    F: Fn(Vec3) -> Float,
+{
+    fn distance(&self, p: Vec3) -> Float {
+        self(p)
+    }
+}
+
+pub struct SdfSphere {
+    pub center: Vec3,
+    pub radius: Float,
+}
+
+impl SdfSphere {
+    pub fn new(center: Vec3, radius: Float) -> Self {
+        SdfSphere { center, radius }
+    }
+}
+
+impl Sdf for SdfSphere {
+    fn distance(&self, p: Vec3) -> Float {
+        (p - self.center).mag() - self.radius
+    }
+}
+
+/// Axis-aligned box with a given center and half of its size along each axis.
+/// A positive `rounding` rounds off the edges with that radius, growing the box by the same amount.
+pub struct SdfBox {
+    pub center: Vec3,
+    pub half_extents: Vec3,
+    pub rounding: Float,
+}
+
+impl SdfBox {
+    pub fn new(center: Vec3, half_extents: Vec3, rounding: Float) -> Self {
+        SdfBox {
+            center,
+            half_extents,
+            rounding,
+        }
+    }
+}
+
+impl Sdf for SdfBox {
+    fn distance(&self, p: Vec3) -> Float {
+        let d = p - self.center;
+        let q = Vec3::new(d.x.abs(), d.y.abs(), d.z.abs()) - self.half_extents;
+        let outside = Vec3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).mag();
+        let inside = q.x.max(q.y).max(q.z).min(0.0);
+        outside + inside - self.rounding
+    }
+}
+
+/// Torus around `center` with the ring in the xy plane.
+pub struct SdfTorus {
+    pub center: Vec3,
+    pub major_radius: Float,
+    pub minor_radius: Float,
+}
+
+impl SdfTorus {
+    pub fn new(center: Vec3, major_radius: Float, minor_radius: Float) -> Self {
+        SdfTorus {
+            center,
+            major_radius,
+            minor_radius,
+        }
+    }
+}
+
+impl Sdf for SdfTorus {
+    fn distance(&self, p: Vec3) -> Float {
+        let d = p - self.center;
+        let ring = (d.x * d.x + d.y * d.y).sqrt() - self.major_radius;
+        (ring * ring + d.z * d.z).sqrt() - self.minor_radius
+    }
+}
+
+/// Line segment from `a` to `b` with rounded ends.
+pub struct SdfCapsule {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub radius: Float,
+}
+
+impl SdfCapsule {
+    pub fn new(a: Vec3, b: Vec3, radius: Float) -> Self {
+        SdfCapsule { a, b, radius }
+    }
+}
+
+impl Sdf for SdfCapsule {
+    fn distance(&self, p: Vec3) -> Float {
+        let pa = p - self.a;
+        let ba = self.b - self.a;
+        let h = (pa.dot(&ba) / ba.dot(&ba)).clamp(0.0, 1.0);
+        (pa - ba * h).mag() - self.radius
+    }
+}
+
+/// The Mandelbulb fractal centered at the origin. It fits in a sphere of radius about 1.2 for `power` 8.
+/// Place it with an Instance to move or scale it.
+pub struct Mandelbulb {
+    pub power: Float,
+    pub iterations: usize,
+}
+
+impl Mandelbulb {
+    /// Panics if `iterations` is 0.
+    pub fn new(power: Float, iterations: usize) -> Self {
+        assert!(iterations > 0, "a Mandelbulb needs at least one iteration");
+        Mandelbulb { power, iterations }
+    }
+}
+
+impl Sdf for Mandelbulb {
+    // Distance estimator based on the running derivative of the iteration.
+    fn distance(&self, p: Vec3) -> Float {
+        let mut z = p;
+        let mut dr = 1.0;
+        let mut r = 0.0;
+        for _ in 0..self.iterations {
+            r = z.mag();
+            // The origin is a fixed point, and has no direction to raise to the power.
+            if r > 2.0 || r == 0.0 {
+                break;
+            }
+            let theta = (z.z / r).acos() * self.power;
+            let phi = z.y.atan2(z.x) * self.power;
+            dr = r.powf(self.power - 1.0) * self.power * dr + 1.0;
+            let zr = r.powf(self.power);
+            z = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.sin() * phi.sin(),
+                theta.cos(),
+            ) * zr
+                + p;
+        }
+        if r == 0.0 {
+            return 0.0;
+        }
+        0.5 * r.ln() * r / dr
+    }
+}
+
+/// How two distance fields are combined by SdfCombine.
+#[derive(Debug, Clone, Copy)]
+pub enum SdfOperation {
+    Union,
+    Intersection,
+    /// The first field with the second one cut out.
+    Difference,
+    /// Union with the seam rounded off over a distance of about k.
+    SmoothUnion(Float),
+    SmoothIntersection(Float),
+    SmoothDifference(Float),
+    /// Linear interpolation between the two fields, from the first at 0 to the second at 1.
+    Blend(Float),
+}
+
+/// Polynomial smooth minimum. Implementation is based on Inigo Quilez, "Smooth minimum".
+fn smooth_min(a: Float, b: Float, k: Float) -> Float {
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+pub struct SdfCombine<A: Sdf, B: Sdf> {
+    a: A,
+    b: B,
+    operation: SdfOperation,
+}
+
+impl<A, B> SdfCombine<A, B>
+where
+    A: Sdf,
+    B: Sdf,
+{
+    pub fn new(a: A, b: B, operation: SdfOperation) -> Self {
+        SdfCombine { a, b, operation }
+    }
+}
+
+impl<A, B> Sdf for SdfCombine<A, B>
+where
+    A: Sdf,
+    B: Sdf,
+{
+    fn distance(&self, p: Vec3) -> Float {
+        let a = self.a.distance(p);
+        let b = self.b.distance(p);
+        match self.operation {
+            SdfOperation::Union => a.min(b),
+            SdfOperation::Intersection => a.max(b),
+            SdfOperation::Difference => a.max(-b),
+            SdfOperation::SmoothUnion(k) => smooth_min(a, b, k),
+            SdfOperation::SmoothIntersection(k) => -smooth_min(-a, -b, k),
+            SdfOperation::SmoothDifference(k) => -smooth_min(-a, b, k),
+            SdfOperation::Blend(t) => a + (b - a) * t,
+        }
+    }
+}
+
+// Maximum number of sphere tracing steps per ray.
+const MAX_STEPS: usize = 256;
+// A point closer to the surface than this counts as a hit. Smaller than math::EPSILON, so bounced rays leave the surface.
+const HIT_DISTANCE: Float = math::EPSILON * 0.1;
+
+/// An object whose surface is the zero set of a distance field, rendered by sphere tracing.
+/// The object has to lie within `bounds`, where the tracing starts and ends.
+pub struct SdfObject<S: Sdf, R: Reflection> {
+    sdf: S,
+    bounds: Aabb,
+    reflection: R,
+}
+
+impl<S, R> SdfObject<S, R>
+where
+    S: Sdf,
+    R: Reflection,
+{
+    pub fn new(sdf: S, bounds: Aabb, reflection: R) -> Self {
+        SdfObject {
+            sdf,
+            bounds,
+            reflection,
+        }
+    }
+
+    /// The normal is the gradient of the distance field, estimated by central differences.
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let e = math::EPSILON;
+        let dx = Vec3::new(e, 0.0, 0.0);
+        let dy = Vec3::new(0.0, e, 0.0);
+        let dz = Vec3::new(0.0, 0.0, e);
+        Vec3::new(
+            self.sdf.distance(p + dx) - self.sdf.distance(p - dx),
+            self.sdf.distance(p + dy) - self.sdf.distance(p - dy),
+            self.sdf.distance(p + dz) - self.sdf.distance(p - dz),
+        )
+        .normalize()
+    }
+}
+
+impl<S, R> Hittable for SdfObject<S, R>
+where
+    S: Sdf,
+    R: Reflection,
+{
+    fn intersect(&self, h: &HitAttr) -> Option<SurfaceInteraction<'_>> {
+        let (t_0, t_1) = self.bounds.intersect(&h.ray)?;
+        let mut t = t_0;
+        // Rays starting inside the object(e.g. Glass) march towards the surface from the inside.
+        let side = self.sdf.distance(h.ray.at(t)).signum();
+        for _ in 0..MAX_STEPS {
+            let d = self.sdf.distance(h.ray.at(t)) * side;
+            // A broken distance estimate would never reach the surface or the end of the bounds.
+            if !d.is_finite() {
+                return None;
+            }
+            if d < HIT_DISTANCE {
+                if !h.ray.contains(t) {
+                    return None;
+                }
+                // A distance field has no natural parametrization, so there are no uv coordinates.
+                let normal = self.normal(h.ray.at(t));
+                return Some(SurfaceInteraction::new(
+                    t,
+                    h,
+                    normal,
+                    [0.0, 0.0],
+                    tangent_frame(normal),
+                    &self.reflection,
+                ));
+            }
+            t += d;
+            if t > t_1 {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+}
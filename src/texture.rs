@@ -0,0 +1,161 @@
+use crate::math::{Float, Vec3};
+use std::path::Path;
+
+/// A value that varies over a surface, e.g. a color or a blending weight.
+/// It is sampled at the surface coordinates `uv` and the point `p` where a ray hits the surface.
+pub trait Texture<T> {
+    fn value(&self, uv: [Float; 2], p: Vec3) -> T;
+}
+
+/// A plain number is the same everywhere.
+impl Texture<Float> for Float {
+    fn value(&self, _uv: [Float; 2], _p: Vec3) -> Float {
+        *self
+    }
+}
+
+/// A plain color is the same everywhere.
+impl Texture<Vec3> for Vec3 {
+    fn value(&self, _uv: [Float; 2], _p: Vec3) -> Vec3 {
+        *self
+    }
+}
+
+/// A checkerboard alternating between two textures, with `frequency` squares per unit of u and v.
+pub struct Checker<A, B> {
+    even: A,
+    odd: B,
+    frequency: Float,
+}
+
+impl<A, B> Checker<A, B> {
+    pub fn new(even: A, odd: B, frequency: Float) -> Self {
+        Self {
+            even,
+            odd,
+            frequency,
+        }
+    }
+}
+
+impl<T, A, B> Texture<T> for Checker<A, B>
+where
+    A: Texture<T>,
+    B: Texture<T>,
+{
+    fn value(&self, uv: [Float; 2], p: Vec3) -> T {
+        let i = (uv[0] * self.frequency).floor() as i64;
+        let j = (uv[1] * self.frequency).floor() as i64;
+        if (i + j).rem_euclid(2) == 0 {
+            self.even.value(uv, p)
+        } else {
+            self.odd.value(uv, p)
+        }
+    }
+}
+
+/// How an ImageTexture continues outside of uv in [0, 1].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapMode {
+    /// Tiles the image.
+    Repeat,
+    /// Tiles the image, mirrored in every other tile so the edges line up.
+    Mirror,
+    /// Extends the pixels at the edges.
+    Clamp,
+}
+
+impl WrapMode {
+    /// Maps a pixel index into an axis with n pixels.
+    fn wrap(self, i: i64, n: usize) -> usize {
+        let n = n as i64;
+        let i = match self {
+            WrapMode::Repeat => i.rem_euclid(n),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n {
+                    i
+                } else {
+                    2 * n - 1 - i
+                }
+            }
+            WrapMode::Clamp => i.clamp(0, n - 1),
+        };
+        i as usize
+    }
+}
+
+/// A color texture from an image, bilinearly filtered between the pixels.
+/// The image spans uv in [0, 1], with the top row of the image at v = 1.
+/// As a number(e.g. the weight of a Mix), the texture is the luminance of the color.
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+    wrap: WrapMode,
+}
+
+impl ImageTexture {
+    /// Constructs a texture from `width * height` pixels, stored row by row starting at the top. The image repeats.
+    /// Panics if the image is empty or the number of pixels does not match the size.
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> Self {
+        assert!(width > 0 && height > 0, "an image texture needs pixels");
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "expected {width}x{height} pixels"
+        );
+        ImageTexture {
+            width,
+            height,
+            pixels,
+            wrap: WrapMode::Repeat,
+        }
+    }
+
+    /// Loads an image in any format the image crate supports.
+    /// The pixels are decoded with a gamma of 2, the inverse of how the rendered image is encoded.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.into_rgb8();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let pixels = image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0.map(|c| (c as Float / 255.0).powi(2));
+                Vec3::new(r, g, b)
+            })
+            .collect();
+        Ok(Self::new(width, height, pixels))
+    }
+
+    pub fn with_wrap(self, wrap: WrapMode) -> Self {
+        ImageTexture { wrap, ..self }
+    }
+
+    fn pixel(&self, i: i64, j: i64) -> Vec3 {
+        let i = self.wrap.wrap(i, self.width);
+        let j = self.wrap.wrap(j, self.height);
+        self.pixels[j * self.width + i]
+    }
+}
+
+impl Texture<Vec3> for ImageTexture {
+    fn value(&self, uv: [Float; 2], _p: Vec3) -> Vec3 {
+        // Pixel centers lie at half-integer coordinates.
+        let x = uv[0] * self.width as Float - 0.5;
+        let y = (1.0 - uv[1]) * self.height as Float - 0.5;
+        let (i, j) = (x.floor(), y.floor());
+        let (s, t) = (x - i, y - j);
+        let (i, j) = (i as i64, j as i64);
+        let top = self.pixel(i, j) * (1.0 - s) + self.pixel(i + 1, j) * s;
+        let bottom = self.pixel(i, j + 1) * (1.0 - s) + self.pixel(i + 1, j + 1) * s;
+        top * (1.0 - t) + bottom * t
+    }
+}
+
+impl Texture<Float> for ImageTexture {
+    fn value(&self, uv: [Float; 2], p: Vec3) -> Float {
+        let color: Vec3 = self.value(uv, p);
+        0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+    }
+}